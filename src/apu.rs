@@ -0,0 +1,926 @@
+use crate::savestate::StateIo;
+
+// Audio Processing Unit: 2 square channels, 1 wave channel, 1 noise channel.
+// One stereo sample is produced every M-cycle (4 T-cycles), i.e. at 1048576 Hz.
+
+pub const SAMPLE_RATE : u32 = 1_048_576;
+const MAX_SAMPLES : usize = SAMPLE_RATE as usize;	// Samples kept when nobody consumes them (0.5 s)
+
+// Sound registers
+pub const NR10 : u16 = 0xFF10;
+pub const NR52 : u16 = 0xFF26;
+pub const WAVE_RAM : u16 = 0xFF30;
+pub const PCM12 : u16 = 0xFF76;	//CGB only: digital outputs of channels 1 and 2
+pub const PCM34 : u16 = 0xFF77;	//CGB only: digital outputs of channels 3 and 4
+
+// Bits always read as 1 for each register from NR10 (0xFF10) to 0xFF2F
+const READ_MASKS : [u8; 0x20] =
+[
+	0x80, 0x3F, 0x00, 0xFF, 0xBF,	// NR10-NR14
+	0xFF, 0x3F, 0x00, 0xFF, 0xBF,	// NR20-NR24
+	0x7F, 0xFF, 0x9F, 0xFF, 0xBF,	// NR30-NR34
+	0xFF, 0xFF, 0x00, 0x00, 0xBF,	// NR40-NR44
+	0x00, 0x00, 0x70,				// NR50-NR52
+	0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const DUTY_TABLE : [[u8; 8]; 4] =
+[
+	[0, 0, 0, 0, 0, 0, 0, 1],	// 12.5%
+	[1, 0, 0, 0, 0, 0, 0, 1],	// 25%
+	[1, 0, 0, 0, 0, 1, 1, 1],	// 50%
+	[0, 1, 1, 1, 1, 1, 1, 0],	// 75%
+];
+
+const NOISE_DIVISORS : [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Length counter: disables the channel when it reaches 0 (clocked at 256 Hz)
+pub struct Length
+{
+	pub counter : u16,
+	pub enabled : bool,
+	max : u16,
+}
+
+impl Length
+{
+	fn init_length(max : u16) -> Length
+	{
+		Length { counter : 0, enabled : false, max }
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u16(&mut self.counter);
+		state.bool(&mut self.enabled);
+	}
+
+	fn load(&mut self, value : u16)
+	{
+		self.counter = self.max - value;
+	}
+
+	// NRx4 write. Enabling the counter while the next frame sequencer step doesn't clock it
+	// clocks it once more (extra_clock), returns true if the channel must be disabled.
+	fn write_control(&mut self, enabled : bool, trigger : bool, extra_clock : bool) -> bool
+	{
+		let was_enabled = self.enabled;
+		self.enabled = enabled;
+
+		let mut disable = false;
+		if extra_clock && !was_enabled && enabled && self.counter > 0
+		{
+			self.counter -= 1;
+			disable = self.counter == 0 && !trigger;
+		}
+
+		// A TRIGGER RELOADS AN EXPIRED COUNTER, THE EXTRA CLOCK APPLIES TO THE NEW VALUE
+		if trigger && self.counter == 0
+		{
+			self.counter = self.max;
+			if extra_clock && enabled
+			{
+				self.counter -= 1;
+			}
+		}
+		disable
+	}
+
+	// Returns true if the channel must be disabled
+	fn clock(&mut self) -> bool
+	{
+		if self.enabled && self.counter > 0
+		{
+			self.counter -= 1;
+			return self.counter == 0;
+		}
+		false
+	}
+}
+
+// Volume envelope (clocked at 64 Hz)
+pub struct Envelope
+{
+	pub initial : u8,
+	pub increase : bool,
+	pub period : u8,
+	pub volume : u8,
+	timer : u8,
+	running : bool,						//Stops once the volume reaches 0 or 15
+}
+
+impl Envelope
+{
+	fn init_envelope() -> Envelope
+	{
+		Envelope { initial : 0, increase : false, period : 0, volume : 0, timer : 0, running : false }
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u8_upto(&mut self.initial, 15);
+		state.bool(&mut self.increase);
+		state.u8_upto(&mut self.period, 7);
+		state.u8_upto(&mut self.volume, 15);
+		state.u8_upto(&mut self.timer, 7);
+		state.bool(&mut self.running);
+	}
+
+	fn write(&mut self, value : u8, channel_enabled : bool)
+	{
+		// ZOMBIE MODE: WRITING NRx2 WHILE THE CHANNEL PLAYS CHANGES THE CURRENT VOLUME
+		let increase = value & 0x08 != 0;
+		if channel_enabled
+		{
+			if self.period == 0 && self.running
+			{
+				self.volume += 1;
+			}
+			else if !self.increase
+			{
+				self.volume += 2;
+			}
+			if increase != self.increase
+			{
+				self.volume = 16u8.wrapping_sub(self.volume);
+			}
+			self.volume &= 0x0F;
+		}
+
+		self.initial = value >> 4;
+		self.increase = value & 0x08 != 0;
+		self.period = value & 0x07;
+	}
+
+	fn trigger(&mut self)
+	{
+		self.volume = self.initial;
+		self.timer = self.period;
+		self.running = true;
+	}
+
+	fn clock(&mut self)
+	{
+		if self.period == 0
+		{
+			return;
+		}
+		self.timer = self.timer.saturating_sub(1);
+		if self.timer == 0
+		{
+			self.timer = self.period;
+			if self.increase && self.volume < 15 && self.running
+			{
+				self.volume += 1;
+			}
+			else if !self.increase && self.volume > 0 && self.running
+			{
+				self.volume -= 1;
+			}
+			else
+			{
+				self.running = false;
+			}
+		}
+	}
+}
+
+// Channels 1 and 2 (only channel 1 has the frequency sweep)
+pub struct Square
+{
+	pub enabled : bool,
+	pub dac : bool,
+	pub duty : u8,
+	pub duty_step : usize,
+	pub frequency : u16,
+	timer : i32,
+	pub length : Length,
+	pub envelope : Envelope,
+
+	has_sweep : bool,
+	pub sweep_period : u8,
+	pub sweep_negate : bool,
+	pub sweep_shift : u8,
+	sweep_timer : u8,
+	sweep_enabled : bool,
+	sweep_shadow : u16,
+	sweep_negated : bool,				//A negate calculation happened since the trigger
+}
+
+impl Square
+{
+	fn init_square(has_sweep : bool) -> Square
+	{
+		Square
+		{
+			enabled : false,
+			dac : false,
+			duty : 0,
+			duty_step : 0,
+			frequency : 0,
+			timer : 0,
+			length : Length::init_length(64),
+			envelope : Envelope::init_envelope(),
+			has_sweep,
+			sweep_period : 0,
+			sweep_negate : false,
+			sweep_shift : 0,
+			sweep_timer : 0,
+			sweep_enabled : false,
+			sweep_shadow : 0,
+			sweep_negated : false,
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.bool(&mut self.enabled);
+		state.bool(&mut self.dac);
+		state.u8_upto(&mut self.duty, 3);
+		state.index(&mut self.duty_step, 8);
+		state.u16(&mut self.frequency);
+		state.i32(&mut self.timer);
+		self.length.sync_state(state);
+		self.envelope.sync_state(state);
+		state.u8_upto(&mut self.sweep_period, 7);
+		state.bool(&mut self.sweep_negate);
+		state.u8_upto(&mut self.sweep_shift, 7);
+		state.u8_upto(&mut self.sweep_timer, 8);
+		state.bool(&mut self.sweep_enabled);
+		state.u16(&mut self.sweep_shadow);
+		state.bool(&mut self.sweep_negated);
+	}
+
+	fn write(&mut self, register : u16, value : u8, extra_length_clock : bool)
+	{
+		match register
+		{
+			0 =>
+			{
+				self.sweep_period = (value >> 4) & 0x07;
+				self.sweep_negate = value & 0x08 != 0;
+				self.sweep_shift = value & 0x07;
+
+				// LEAVING NEGATE MODE AFTER A NEGATE CALCULATION DISABLES THE CHANNEL
+				if self.sweep_negated && !self.sweep_negate
+				{
+					self.enabled = false;
+				}
+			},
+			1 =>
+			{
+				self.duty = value >> 6;
+				self.length.load((value & 0x3F) as u16);
+			},
+			2 =>
+			{
+				self.envelope.write(value, self.enabled);
+				self.dac = value & 0xF8 != 0;
+				self.enabled &= self.dac;
+			},
+			3 => self.frequency = (self.frequency & 0x700) | value as u16,
+			4 =>
+			{
+				self.frequency = (self.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+				let trigger = value & 0x80 != 0;
+				if self.length.write_control(value & 0x40 != 0, trigger, extra_length_clock)
+				{
+					self.enabled = false;
+				}
+				if trigger
+				{
+					self.trigger();
+				}
+			},
+			_ => (),
+		}
+	}
+
+	fn trigger(&mut self)
+	{
+		self.enabled = self.dac;
+		self.timer = (2048 - self.frequency as i32) * 4;
+		self.envelope.trigger();
+
+		if self.has_sweep
+		{
+			self.sweep_negated = false;
+			self.sweep_shadow = self.frequency;
+			self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+			self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+			if self.sweep_shift != 0
+			{
+				self.sweep_frequency();	// OVERFLOW CHECK
+			}
+		}
+	}
+
+	// New sweep frequency, disables the channel on overflow
+	fn sweep_frequency(&mut self) -> u16
+	{
+		let delta = self.sweep_shadow >> self.sweep_shift;
+		let frequency = if self.sweep_negate { self.sweep_shadow - delta } else { self.sweep_shadow + delta };
+		self.sweep_negated |= self.sweep_negate;
+		if frequency > 2047
+		{
+			self.enabled = false;
+		}
+		frequency
+	}
+
+	fn clock_sweep(&mut self)
+	{
+		self.sweep_timer = self.sweep_timer.saturating_sub(1);
+		if self.sweep_timer != 0
+		{
+			return;
+		}
+		self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+
+		if self.sweep_enabled && self.sweep_period != 0
+		{
+			let frequency = self.sweep_frequency();
+			if frequency <= 2047 && self.sweep_shift != 0
+			{
+				self.sweep_shadow = frequency;
+				self.frequency = frequency;
+				self.sweep_frequency();
+			}
+		}
+	}
+
+	fn step(&mut self, cycles : u32)
+	{
+		self.timer -= cycles as i32;
+		while self.timer <= 0
+		{
+			self.timer += (2048 - self.frequency as i32) * 4;
+			self.duty_step = (self.duty_step + 1) & 0x07;
+		}
+	}
+
+	// Digital output (0-15)
+	fn output(&self) -> u8
+	{
+		if !self.enabled
+		{
+			return 0;
+		}
+		DUTY_TABLE[self.duty as usize][self.duty_step] * self.envelope.volume
+	}
+}
+
+// Channel 3: plays 32 4-bit samples from the wave RAM
+pub struct Wave
+{
+	pub enabled : bool,
+	pub dac : bool,
+	pub volume_code : u8,
+	pub frequency : u16,
+	timer : i32,
+	pub position : usize,
+	pub length : Length,
+	pub wave_ram : [u8; 16],
+	just_read : bool,					//A sample was fetched during the last M-cycle
+}
+
+impl Wave
+{
+	fn init_wave() -> Wave
+	{
+		Wave
+		{
+			enabled : false,
+			dac : false,
+			volume_code : 0,
+			frequency : 0,
+			timer : 0,
+			position : 0,
+			length : Length::init_length(256),
+			wave_ram : [0; 16],
+			just_read : false,
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.bool(&mut self.enabled);
+		state.bool(&mut self.dac);
+		state.u8_upto(&mut self.volume_code, 3);
+		state.u16(&mut self.frequency);
+		state.i32(&mut self.timer);
+		state.index(&mut self.position, 32);
+		self.length.sync_state(state);
+		state.bytes(&mut self.wave_ram);
+		state.bool(&mut self.just_read);
+	}
+
+	fn write(&mut self, register : u16, value : u8, extra_length_clock : bool)
+	{
+		match register
+		{
+			0 =>
+			{
+				self.dac = value & 0x80 != 0;
+				self.enabled &= self.dac;
+			},
+			1 => self.length.load(value as u16),
+			2 => self.volume_code = (value >> 5) & 0x03,
+			3 => self.frequency = (self.frequency & 0x700) | value as u16,
+			4 =>
+			{
+				self.frequency = (self.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+				let trigger = value & 0x80 != 0;
+				if self.length.write_control(value & 0x40 != 0, trigger, extra_length_clock)
+				{
+					self.enabled = false;
+				}
+				if trigger
+				{
+					self.trigger();
+				}
+			},
+			_ => (),
+		}
+	}
+
+	fn trigger(&mut self)
+	{
+		self.enabled = self.dac;
+		self.timer = (2048 - self.frequency as i32) * 2;
+		self.position = 0;
+	}
+
+	fn step(&mut self, cycles : u32)
+	{
+		self.timer -= cycles as i32;
+		self.just_read = false;
+		while self.timer <= 0
+		{
+			self.timer += (2048 - self.frequency as i32) * 2;
+			self.position = (self.position + 1) & 0x1F;
+			self.just_read = true;
+		}
+	}
+
+	// Wave RAM index seen by the CPU. While the channel plays, the CPU accesses the byte
+	// being played: always on CGB, only right after the channel fetched it on DMG.
+	fn wave_ram_index(&self, address : u16, cgb : bool) -> Option<usize>
+	{
+		if !self.enabled
+		{
+			return Some((address - WAVE_RAM) as usize);
+		}
+		if cgb || self.just_read
+		{
+			return Some(self.position / 2);
+		}
+		None
+	}
+
+	fn output(&self) -> u8
+	{
+		if !self.enabled
+		{
+			return 0;
+		}
+		let byte = self.wave_ram[self.position / 2];
+		let sample = if self.position & 0x01 == 0 { byte >> 4 } else { byte & 0x0F };
+		match self.volume_code
+		{
+			0 => 0,
+			code => sample >> (code - 1),
+		}
+	}
+}
+
+// Channel 4: pseudo-random noise from a 15-bit (or 7-bit) LFSR
+pub struct Noise
+{
+	pub enabled : bool,
+	pub dac : bool,
+	pub clock_shift : u8,
+	pub width_7 : bool,
+	pub divisor : u8,
+	timer : i32,
+	pub lfsr : u16,
+	pub length : Length,
+	pub envelope : Envelope,
+}
+
+impl Noise
+{
+	fn init_noise() -> Noise
+	{
+		Noise
+		{
+			enabled : false,
+			dac : false,
+			clock_shift : 0,
+			width_7 : false,
+			divisor : 0,
+			timer : 0,
+			lfsr : 0x7FFF,
+			length : Length::init_length(64),
+			envelope : Envelope::init_envelope(),
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.bool(&mut self.enabled);
+		state.bool(&mut self.dac);
+		state.u8_upto(&mut self.clock_shift, 15);
+		state.bool(&mut self.width_7);
+		state.u8_upto(&mut self.divisor, 7);
+		state.i32(&mut self.timer);
+		state.u16(&mut self.lfsr);
+		self.length.sync_state(state);
+		self.envelope.sync_state(state);
+	}
+
+	fn write(&mut self, register : u16, value : u8, extra_length_clock : bool)
+	{
+		match register
+		{
+			1 => self.length.load((value & 0x3F) as u16),
+			2 =>
+			{
+				self.envelope.write(value, self.enabled);
+				self.dac = value & 0xF8 != 0;
+				self.enabled &= self.dac;
+			},
+			3 =>
+			{
+				self.clock_shift = value >> 4;
+				self.width_7 = value & 0x08 != 0;
+				self.divisor = value & 0x07;
+			},
+			4 =>
+			{
+				let trigger = value & 0x80 != 0;
+				if self.length.write_control(value & 0x40 != 0, trigger, extra_length_clock)
+				{
+					self.enabled = false;
+				}
+				if trigger
+				{
+					self.trigger();
+				}
+			},
+			_ => (),
+		}
+	}
+
+	fn period(&self) -> i32
+	{
+		(NOISE_DIVISORS[self.divisor as usize] << self.clock_shift) as i32
+	}
+
+	fn trigger(&mut self)
+	{
+		self.enabled = self.dac;
+		self.timer = self.period();
+		self.envelope.trigger();
+		self.lfsr = 0x7FFF;
+	}
+
+	fn step(&mut self, cycles : u32)
+	{
+		self.timer -= cycles as i32;
+		while self.timer <= 0
+		{
+			self.timer += self.period();
+
+			// XOR OF THE 2 LOWEST BITS IS SHIFTED IN AT BIT 14 (AND BIT 6 IN 7-BIT MODE)
+			let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+			self.lfsr = (self.lfsr >> 1) | (bit << 14);
+			if self.width_7
+			{
+				self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+			}
+		}
+	}
+
+	fn output(&self) -> u8
+	{
+		if !self.enabled || self.lfsr & 0x01 != 0
+		{
+			return 0;
+		}
+		self.envelope.volume
+	}
+}
+
+pub struct Apu
+{
+	pub cgb : bool,						//CGB hardware (wave RAM access, PCM12/PCM34)
+	pub power : bool,					//NR52 bit 7
+	pub ch1 : Square,
+	pub ch2 : Square,
+	pub ch3 : Wave,
+	pub ch4 : Noise,
+	pub nr50 : u8,						//Master volume (left bits 4-6, right bits 0-2)
+	pub nr51 : u8,						//Panning (left bits 4-7, right bits 0-3)
+	registers : [u8; 0x20],				//Last values written to NR10-NR52, for reads
+
+	frame_step : u8,					//Frame sequencer step (0-7), clocked at 512 Hz
+	last_div_bit : bool,				//DIV bit driving the frame sequencer
+	cycles : u32,						//T-cycles not yet turned into samples
+	capacitor : [f32; 2],				//High-pass filter state (left, right)
+	channel_capacitors : [[f32; 2]; 4],	//High-pass filter state of each channel output
+
+	pub muted : [bool; 4],				//Channels removed from the mix
+	pub solo : Option<usize>,			//Only channel kept in the mix
+	pub samples : Vec<f32>,				//Interleaved stereo samples at SAMPLE_RATE
+	pub split_channels : bool,			//Also output each channel separately
+	pub channel_samples : [Vec<f32>; 4],	//Interleaved stereo samples of each channel (not muted)
+}
+
+impl Apu
+{
+	pub fn init_apu() -> Apu
+	{
+		Apu
+		{
+			cgb : false,
+			power : false,
+			ch1 : Square::init_square(true),
+			ch2 : Square::init_square(false),
+			ch3 : Wave::init_wave(),
+			ch4 : Noise::init_noise(),
+			nr50 : 0,
+			nr51 : 0,
+			registers : [0; 0x20],
+			frame_step : 0,
+			last_div_bit : false,
+			cycles : 0,
+			capacitor : [0.0; 2],
+			channel_capacitors : [[0.0; 2]; 4],
+			muted : [false; 4],
+			solo : None,
+			samples : Vec::new(),
+			split_channels : false,
+			channel_samples : Default::default(),
+		}
+	}
+
+	// THE MUTED CHANNELS AND THE PENDING SAMPLES BELONG TO THE FRONTEND, THEY ARE NOT SAVED
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.bool(&mut self.power);
+		self.ch1.sync_state(state);
+		self.ch2.sync_state(state);
+		self.ch3.sync_state(state);
+		self.ch4.sync_state(state);
+		state.u8(&mut self.nr50);
+		state.u8(&mut self.nr51);
+		state.bytes(&mut self.registers);
+		state.u8_upto(&mut self.frame_step, 7);
+		state.bool(&mut self.last_div_bit);
+		state.u32(&mut self.cycles);
+		for value in self.capacitor.iter_mut().chain(self.channel_capacitors.iter_mut().flatten())
+		{
+			state.f32(value);
+		}
+	}
+
+	pub fn read_byte(&self, address : u16) -> u8
+	{
+		match address
+		{
+			NR52 =>
+			{
+				0x70 | (self.power as u8) << 7
+					| (self.ch4.enabled as u8) << 3
+					| (self.ch3.enabled as u8) << 2
+					| (self.ch2.enabled as u8) << 1
+					| self.ch1.enabled as u8
+			},
+			0xFF10..=0xFF2F => self.registers[(address - NR10) as usize] | READ_MASKS[(address - NR10) as usize],
+			0xFF30..=0xFF3F => self.ch3.wave_ram_index(address, self.cgb).map_or(0xFF, |i| self.ch3.wave_ram[i]),
+			PCM12 if self.cgb => self.ch2.output() << 4 | self.ch1.output(),
+			PCM34 if self.cgb => self.ch4.output() << 4 | self.ch3.output(),
+			_ => 0xFF,
+		}
+	}
+
+	pub fn write_byte(&mut self, address : u16, value : u8)
+	{
+		// THE LAST FRAME SEQUENCER STEP CLOCKED THE LENGTH COUNTERS, THE NEXT ONE WON'T
+		let extra_length_clock = self.frame_step & 0x01 != 0;
+		match address
+		{
+			NR52 =>
+			{
+				let power = value & 0x80 != 0;
+				if self.power && !power
+				{
+					self.power_off();
+				}
+				else if !self.power && power
+				{
+					self.frame_step = 0;	// THE NEXT FRAME SEQUENCER STEP IS 0
+				}
+				self.power = power;
+			},
+			0xFF30..=0xFF3F =>
+			{
+				if let Some(i) = self.ch3.wave_ram_index(address, self.cgb)
+				{
+					self.ch3.wave_ram[i] = value;
+				}
+			},
+			// ON DMG THE LENGTH COUNTERS CAN STILL BE LOADED WHILE POWERED OFF
+			0xFF11 if !self.power && !self.cgb => self.ch1.length.load((value & 0x3F) as u16),
+			0xFF16 if !self.power && !self.cgb => self.ch2.length.load((value & 0x3F) as u16),
+			0xFF1B if !self.power && !self.cgb => self.ch3.length.load(value as u16),
+			0xFF20 if !self.power && !self.cgb => self.ch4.length.load((value & 0x3F) as u16),
+			_ if !self.power => (),	// REGISTERS ARE READ ONLY WHILE POWERED OFF
+			0xFF10..=0xFF14 => self.ch1.write(address - 0xFF10, value, extra_length_clock),
+			0xFF15..=0xFF19 => self.ch2.write(address - 0xFF15, value, extra_length_clock),
+			0xFF1A..=0xFF1E => self.ch3.write(address - 0xFF1A, value, extra_length_clock),
+			0xFF1F..=0xFF23 => self.ch4.write(address - 0xFF1F, value, extra_length_clock),
+			0xFF24 => self.nr50 = value,
+			0xFF25 => self.nr51 = value,
+			_ => (),
+		}
+
+		if (0xFF10..0xFF26).contains(&address) && self.power
+		{
+			self.registers[(address - NR10) as usize] = value;
+		}
+	}
+
+	// Powering off clears every sound register (the wave RAM is kept, and the length counters on DMG)
+	fn power_off(&mut self)
+	{
+		let wave_ram = self.ch3.wave_ram;
+		let lengths = [self.ch1.length.counter, self.ch2.length.counter, self.ch3.length.counter, self.ch4.length.counter];
+		self.ch1 = Square::init_square(true);
+		self.ch2 = Square::init_square(false);
+		self.ch3 = Wave::init_wave();
+		self.ch3.wave_ram = wave_ram;
+		self.ch4 = Noise::init_noise();
+		if !self.cgb
+		{
+			self.ch1.length.counter = lengths[0];
+			self.ch2.length.counter = lengths[1];
+			self.ch3.length.counter = lengths[2];
+			self.ch4.length.counter = lengths[3];
+		}
+		self.nr50 = 0;
+		self.nr51 = 0;
+		self.registers = [0; 0x20];
+	}
+
+	// Advance by a number of T-cycles (4 MHz clock, not affected by double speed).
+	// div_before and div are the internal timer counter before and after the same cycles,
+	// each falling edge of its bit 12 (13 in double speed) clocks the frame sequencer.
+	pub fn step(&mut self, cycles : u32, div_before : u16, div : u16, double_speed : bool)
+	{
+		// A LONG STEP (STOP, VRAM DMA STALL) CAN CROSS SEVERAL EDGES
+		let bit = if double_speed { 13 } else { 12 };
+		let start = div_before as u32;
+		let end = start + div.wrapping_sub(div_before) as u32;
+		let mut edges = (end >> (bit + 1)) - (start >> (bit + 1));
+
+		// A DIV RESET SINCE THE LAST STEP IS AN EDGE WHEN THE BIT WAS SET
+		if self.last_div_bit && (div_before >> bit) & 0x01 == 0
+		{
+			edges += 1;
+		}
+		if self.power
+		{
+			for _ in 0..edges
+			{
+				self.clock_frame_sequencer();
+			}
+		}
+		self.last_div_bit = (div >> bit) & 0x01 != 0;
+
+		self.cycles += cycles;
+		while self.cycles >= 4
+		{
+			self.cycles -= 4;
+			if self.power
+			{
+				self.ch1.step(4);
+				self.ch2.step(4);
+				self.ch3.step(4);
+				self.ch4.step(4);
+			}
+			self.push_sample();
+		}
+	}
+
+	// 512 Hz: length at steps 0, 2, 4, 6 - sweep at steps 2, 6 - envelope at step 7
+	fn clock_frame_sequencer(&mut self)
+	{
+		if self.frame_step & 0x01 == 0
+		{
+			if self.ch1.length.clock() { self.ch1.enabled = false; }
+			if self.ch2.length.clock() { self.ch2.enabled = false; }
+			if self.ch3.length.clock() { self.ch3.enabled = false; }
+			if self.ch4.length.clock() { self.ch4.enabled = false; }
+		}
+		if self.frame_step == 2 || self.frame_step == 6
+		{
+			self.ch1.clock_sweep();
+		}
+		if self.frame_step == 7
+		{
+			self.ch1.envelope.clock();
+			self.ch2.envelope.clock();
+			self.ch4.envelope.clock();
+		}
+		self.frame_step = (self.frame_step + 1) & 0x07;
+	}
+
+	// Digital output of each channel (0-15) and whether its DAC is on
+	pub fn channel_outputs(&self) -> [(u8, bool); 4]
+	{
+		[
+			(self.ch1.output(), self.ch1.dac),
+			(self.ch2.output(), self.ch2.dac),
+			(self.ch3.output(), self.ch3.dac),
+			(self.ch4.output(), self.ch4.dac),
+		]
+	}
+
+	// Whether a channel is part of the mixed output
+	pub fn audible(&self, channel : usize) -> bool
+	{
+		match self.solo
+		{
+			Some(solo) => solo == channel,
+			None => !self.muted[channel],
+		}
+	}
+
+	fn push_sample(&mut self)
+	{
+		let mut left = 0.0;
+		let mut right = 0.0;
+		let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0 / 4.0;
+		let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8.0 / 4.0;
+		for (i, (output, dac)) in self.channel_outputs().iter().enumerate()
+		{
+			// THE DAC MAPS 0..15 TO 1.0..-1.0, A DISABLED DAC OUTPUTS 0
+			let analog = if *dac { 1.0 - *output as f32 / 7.5 } else { 0.0 };
+			let channel_left = if self.nr51 & (0x10 << i) != 0 { analog * left_volume } else { 0.0 };
+			let channel_right = if self.nr51 & (0x01 << i) != 0 { analog * right_volume } else { 0.0 };
+			if self.audible(i)
+			{
+				left += channel_left;
+				right += channel_right;
+			}
+
+			if self.split_channels && self.channel_samples[i].len() < MAX_SAMPLES
+			{
+				let filtered_left = high_pass(&mut self.channel_capacitors[i][0], channel_left);
+				let filtered_right = high_pass(&mut self.channel_capacitors[i][1], channel_right);
+				self.channel_samples[i].push(filtered_left);
+				self.channel_samples[i].push(filtered_right);
+			}
+		}
+
+		if self.samples.len() < MAX_SAMPLES
+		{
+			let left = high_pass(&mut self.capacitor[0], left);
+			let right = high_pass(&mut self.capacitor[1], right);
+			self.samples.push(left);
+			self.samples.push(right);
+		}
+	}
+}
+
+// Capacitor on the output removing the DC offset of the DACs
+fn high_pass(capacitor : &mut f32, input : f32) -> f32
+{
+	let output = input - *capacitor;
+	*capacitor = input - output * 0.999832;	// 0.999958^4, CHARGE FACTOR PER M-CYCLE
+	output
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn frame_sequencer_clocked_by_every_div_edge()
+	{
+		let mut apu = Apu::init_apu();
+		apu.write_byte(NR52, 0x80);
+
+		// 8200 CYCLES OF STOP CROSS 2 FALLING EDGES OF BIT 12
+		apu.step(8200, 0x1FFC, 0x1FFC + 8200, false);
+		assert_eq!(apu.frame_step, 2);
+
+		// A DIV RESET WITH THE BIT SET IS ONE MORE
+		apu.step(4, 0x1000, 0x1004, false);
+		apu.step(4, 0x0000, 0x0004, false);
+		assert_eq!(apu.frame_step, 3);
+	}
+}
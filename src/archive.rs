@@ -0,0 +1,72 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use flate2::read::GzDecoder;
+use crate::cartridge::{RomError, MAX_ROM_SIZE};
+
+// ROMs stored in .zip or .gz archives, the container is detected from the first bytes of the file
+// whatever its extension. A zip archive can hold several files: the first .gb/.gbc one is loaded,
+// unless an entry is named.
+
+const ZIP_MAGIC : [u8; 4] = [0x50, 0x4B, 0x03, 0x04];	// "PK\3\4"
+const GZIP_MAGIC : [u8; 2] = [0x1F, 0x8B];
+
+// Read a ROM file, extracted from its archive if needed
+pub fn read_rom(path : &str, entry : Option<&str>) -> Result<Vec<u8>, RomError>
+{
+	let data = fs::read(path)?;
+	if data.starts_with(&ZIP_MAGIC)
+	{
+		return read_zip(data, entry);
+	}
+	if data.starts_with(&GZIP_MAGIC)
+	{
+		// A GZIP FILE HOLDS A SINGLE ROM
+		return read_bounded(GzDecoder::new(&data[..]));
+	}
+	Ok(data)
+}
+
+fn is_rom_name(name : &str) -> bool
+{
+	let name = name.to_ascii_lowercase();
+	name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+fn read_zip(data : Vec<u8>, entry : Option<&str>) -> Result<Vec<u8>, RomError>
+{
+	let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| RomError::Archive(e.to_string()))?;
+	let name = match entry
+	{
+		Some(name) => name.to_string(),
+		None =>
+		{
+			// FIRST ONE IN THE ARCHIVE ORDER
+			let mut found = None;
+			for i in 0..archive.len()
+			{
+				let file = archive.by_index(i).map_err(|e| RomError::Archive(e.to_string()))?;
+				if file.is_file() && is_rom_name(file.name())
+				{
+					found = Some(file.name().to_string());
+					break;
+				}
+			}
+			found.ok_or(RomError::NoRomInArchive)?
+		},
+	};
+
+	let file = archive.by_name(&name).map_err(|_| RomError::Archive(format!("no entry named {}", name)))?;
+	read_bounded(file)
+}
+
+// Decompressed data, a damaged archive can claim or inflate to any size
+fn read_bounded(reader : impl Read) -> Result<Vec<u8>, RomError>
+{
+	let mut rom = Vec::new();
+	reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom).map_err(|e| RomError::Archive(e.to_string()))?;
+	if rom.len() > MAX_ROM_SIZE
+	{
+		return Err(RomError::Archive(format!("the ROM is bigger than {} MB", MAX_ROM_SIZE / (1024 * 1024))));
+	}
+	Ok(rom)
+}
@@ -0,0 +1,263 @@
+use std::sync::{Arc, Mutex};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+use crate::apu::SAMPLE_RATE;
+
+// Host audio output: the APU samples (1 MHz) are resampled to the device rate and
+// queued in a ring buffer, drained by the audio callback on its own thread.
+
+const DECIMATION : usize = 16;			// First stage: box filter 1 MHz -> 64 kHz
+const ZERO_CROSSINGS : f64 = 16.0;		// Sinc lobes on each side of the second stage kernel
+const PHASES : usize = 512;				// Kernel table entries per input sample
+const BUFFER_SECONDS : f64 = 0.25;		// Ring buffer capacity
+const MAX_RATE_ADJUST : f64 = 0.005;	// Resampling ratio correction used to follow the buffer level
+
+// Stereo band-limited resampler
+pub struct Resampler
+{
+	ratio : f64,						//Input samples per output sample
+	rate_adjust : f64,					//Small correction of the ratio (pacing)
+	position : f64,						//Position of the next output sample in the history
+	history : Vec<[f32; 2]>,			//Decimated input samples
+	accumulator : [f32; 2],
+	accumulated : usize,
+	half_width : f64,					//Kernel half width in input samples
+	kernel : Vec<f32>,					//Windowed sinc from 0 to half_width
+}
+
+impl Resampler
+{
+	pub fn init_resampler(output_rate : u32) -> Resampler
+	{
+		let input_rate = SAMPLE_RATE as f64 / DECIMATION as f64;
+		let ratio = input_rate / output_rate as f64;
+
+		// CUTOFF BELOW THE NYQUIST FREQUENCY OF THE SLOWER RATE (CYCLES PER INPUT SAMPLE)
+		let cutoff = 0.45 * (1.0 / ratio).min(1.0);
+		let half_width = ZERO_CROSSINGS / (2.0 * cutoff);
+
+		// BLACKMAN WINDOWED SINC, THE KERNEL IS SYMMETRIC SO ONLY THE RIGHT HALF IS KEPT
+		let size = (half_width * PHASES as f64) as usize + 1;
+		let kernel = (0..size).map(|i|
+		{
+			let x = i as f64 / PHASES as f64;
+			let t = 2.0 * cutoff * x;
+			let sinc = if t == 0.0 { 1.0 } else { (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t) };
+			let r = std::f64::consts::PI * x / half_width;
+			let window = 0.42 + 0.5 * r.cos() + 0.08 * (2.0 * r).cos();
+			(2.0 * cutoff * sinc * window) as f32
+		}).collect();
+
+		Resampler
+		{
+			ratio,
+			rate_adjust : 1.0,
+			position : 0.0,
+			history : Vec::new(),
+			accumulator : [0.0; 2],
+			accumulated : 0,
+			half_width,
+			kernel,
+		}
+	}
+
+	// Resample interleaved stereo samples, appending the result to output
+	pub fn process(&mut self, input : &[f32], output : &mut Vec<f32>)
+	{
+		// FIRST STAGE: AVERAGE BLOCKS OF DECIMATION SAMPLES
+		for frame in input.chunks_exact(2)
+		{
+			self.accumulator[0] += frame[0];
+			self.accumulator[1] += frame[1];
+			self.accumulated += 1;
+			if self.accumulated == DECIMATION
+			{
+				let scale = 1.0 / DECIMATION as f32;
+				self.history.push([self.accumulator[0] * scale, self.accumulator[1] * scale]);
+				self.accumulator = [0.0; 2];
+				self.accumulated = 0;
+			}
+		}
+
+		// SECOND STAGE: CONVOLUTION WITH THE KERNEL CENTERED ON EACH OUTPUT POSITION
+		let half = self.half_width.ceil() as isize;
+		while self.position + (half as f64) < self.history.len() as f64
+		{
+			let center = self.position.floor() as isize;
+			let mut sum = [0.0; 2];
+			for n in (center - half + 1).max(0)..=(center + half)
+			{
+				let x = (self.position - n as f64).abs();
+				if x < self.half_width
+				{
+					let h = self.kernel[(x * PHASES as f64) as usize];
+					let sample = self.history[n as usize];
+					sum[0] += sample[0] * h;
+					sum[1] += sample[1] * h;
+				}
+			}
+			output.extend_from_slice(&sum);
+			self.position += self.ratio * self.rate_adjust;
+		}
+
+		// DROP THE SAMPLES NO LONGER COVERED BY THE KERNEL
+		let consumed = (self.position.floor() as isize - half).max(0) as usize;
+		if consumed > 0
+		{
+			self.history.drain(..consumed);
+			self.position -= consumed as f64;
+		}
+	}
+
+	// Speed up (< 1.0) or slow down (> 1.0) the consumption of input samples
+	pub fn set_rate_adjust(&mut self, rate_adjust : f64)
+	{
+		self.rate_adjust = rate_adjust.clamp(1.0 - MAX_RATE_ADJUST, 1.0 + MAX_RATE_ADJUST);
+	}
+}
+
+// Fixed size FIFO of interleaved stereo samples shared with the audio callback
+pub struct RingBuffer
+{
+	data : Vec<f32>,
+	start : usize,
+	len : usize,
+	last : [f32; 2],					//Repeated on underrun to avoid clicks
+	popped : usize,
+}
+
+impl RingBuffer
+{
+	pub fn init_ring_buffer(capacity : usize) -> RingBuffer
+	{
+		RingBuffer
+		{
+			data : vec![0.0; capacity],
+			start : 0,
+			len : 0,
+			last : [0.0; 2],
+			popped : 0,
+		}
+	}
+
+	// Queue samples, the ones that don't fit are dropped
+	pub fn push(&mut self, samples : &[f32])
+	{
+		let capacity = self.data.len();
+		for &sample in samples.iter().take(capacity - self.len)
+		{
+			self.data[(self.start + self.len) % capacity] = sample;
+			self.len += 1;
+		}
+	}
+
+	pub fn pop(&mut self) -> f32
+	{
+		let channel = self.popped & 0x01;
+		self.popped += 1;
+		if self.len == 0
+		{
+			// UNDERRUN: HOLD THE LAST FRAME
+			return self.last[channel];
+		}
+		let sample = self.data[self.start];
+		self.start = (self.start + 1) % self.data.len();
+		self.len -= 1;
+		self.last[channel] = sample;
+		sample
+	}
+
+	pub fn available(&self) -> usize
+	{
+		self.len
+	}
+}
+
+pub struct AudioOutput
+{
+	_stream : cpal::Stream,				//Playing while kept alive
+	ring : Arc<Mutex<RingBuffer>>,
+	resampler : Resampler,
+	resampled : Vec<f32>,
+}
+
+impl AudioOutput
+{
+	// Open the default output device, None when there is no usable device
+	pub fn open() -> Option<AudioOutput>
+	{
+		let device = cpal::default_host().default_output_device()?;
+		let config = device.default_output_config().ok()?;
+		let sample_rate = config.sample_rate().0;
+		let capacity = (sample_rate as f64 * BUFFER_SECONDS) as usize * 2;
+		let ring = Arc::new(Mutex::new(RingBuffer::init_ring_buffer(capacity)));
+
+		let stream = match config.sample_format()
+		{
+			cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.config(), ring.clone()),
+			cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.config(), ring.clone()),
+			cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.config(), ring.clone()),
+			_ => return None,
+		}.ok()?;
+		stream.play().ok()?;
+
+		Some(AudioOutput
+		{
+			_stream : stream,
+			ring,
+			resampler : Resampler::init_resampler(sample_rate),
+			resampled : Vec::new(),
+		})
+	}
+
+	// Resample APU samples and queue them for playback
+	pub fn push_samples(&mut self, samples : &[f32])
+	{
+		self.resampled.clear();
+		self.resampler.process(samples, &mut self.resampled);
+		self.ring.lock().unwrap().push(&self.resampled);
+	}
+
+	// Stereo frames waiting to be played
+	pub fn buffered(&self) -> usize
+	{
+		self.ring.lock().unwrap().available() / 2
+	}
+
+	// Nudge the resampling ratio to keep the buffer around target frames
+	pub fn follow_buffer_level(&mut self, target : usize)
+	{
+		let error = (self.buffered() as f64 - target as f64) / target as f64;
+		self.resampler.set_rate_adjust(1.0 + MAX_RATE_ADJUST * error);
+	}
+}
+
+fn build_stream<T>(device : &cpal::Device, config : &cpal::StreamConfig, ring : Arc<Mutex<RingBuffer>>) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+	T : SizedSample + FromSample<f32>,
+{
+	let channels = config.channels as usize;
+	device.build_output_stream(config, move |data : &mut [T], _ : &cpal::OutputCallbackInfo|
+	{
+		let mut ring = ring.lock().unwrap();
+		for frame in data.chunks_mut(channels)
+		{
+			let left = ring.pop();
+			let right = ring.pop();
+			// MONO DEVICES GET THE AVERAGE, EXTRA CHANNELS STAY SILENT
+			match frame.len()
+			{
+				1 => frame[0] = T::from_sample((left + right) * 0.5),
+				_ =>
+				{
+					frame[0] = T::from_sample(left);
+					frame[1] = T::from_sample(right);
+					for sample in frame[2..].iter_mut()
+					{
+						*sample = T::from_sample(0.0);
+					}
+				},
+			}
+		}
+	}, |err| println!("Audio stream error: {}", err), None)
+}
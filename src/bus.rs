@@ -1,16 +1,49 @@
+use crate::timer::*;
+use crate::hdma::*;
+use crate::sgb::*;
+use crate::apu::*;
+use crate::joypad::*;
+use crate::serial::*;
+use crate::infrared::*;
+use crate::cheats::Cheats;
+use crate::savestate::{StateIo, StateError};
+
+// Interrupt flags (IF / IE bits)
+pub const INT_VBLANK : u8 = 0x01;
+pub const INT_STAT : u8 = 0x02;
+pub const INT_TIMER : u8 = 0x04;
+pub const INT_SERIAL : u8 = 0x08;
+pub const INT_JOYPAD : u8 = 0x10;
 
 pub struct MemoryBus
 {
-	pub boot_rom : [u8; 0x100],			// 256 bytes of BOOT ROM
+	pub boot_rom : [u8; 0x900],			// 256 bytes of DMG BOOT ROM or 2304 bytes of CGB BOOT ROM
 	pub rom_bank_0 : [u8; 0x4000],		//16KB ROM Bank 0			(0x0000	-	0x3FFF)
 	pub rom_bank_n : [u8; 0x4000],		//16KB ROM Bank N			(0x4000	-	0x7FFF)
-	pub vram : [u8; 0x2000],			//8KB Video RAM 			(0x8000	-	0x9FFF)
+	pub vram : [[u8; 0x2000]; 2],		//2x8KB Video RAM 			(0x8000	-	0x9FFF)
 	pub ext_ram : [u8; 0x2000],			//8KB External RAM			(0xA000	-	0xBFFF)
-	pub work_ram : [u8; 0x2000],		//8KB Work RAM				(0xC000	-	0xDFFF)
+	pub work_ram : [[u8; 0x1000]; 8],	//8x4KB Work RAM			(0xC000	-	0xDFFF)
 	pub sprite_attrib_ram : [u8; 0xa0],	//160B Sprite Attrib RAM	(0xFE00	-	0xFE9F)
 	pub io_registers : [u8; 0x80],		//127B I/O Registers		(0xFF00	-	0xFF7F)
 	pub high_ram : [u8; 0x80],			//126B High RAM				(0xFF80	-	0xFFFE)
 	pub interrupt_enable : u8,			//1B Interrupt Enable		(0xFFFF)
+
+	pub cgb_mode : bool,				//CGB features enabled
+	pub vram_bank : usize,				//VRAM bank mapped at 0x8000 (VBK)
+	pub wram_bank : usize,				//WRAM bank mapped at 0xD000 (SVBK)
+	pub bg_palette_ram : [u8; 0x40],	//8 BG palettes of 4 colors (BCPS/BCPD)
+	pub obj_palette_ram : [u8; 0x40],	//8 OBJ palettes of 4 colors (OCPS/OCPD)
+	pub double_speed : bool,			//CGB double speed mode (KEY1 bit 7)
+
+	pub timer : Timer,					//DIV, TIMA, TMA, TAC
+	pub apu : Apu,						//Sound registers and wave RAM
+	pub joypad : Joypad,				//JOYP
+	pub serial : Serial,				//SB, SC
+	pub infrared : Infrared,			//RP (CGB)
+	pub hdma : Hdma,					//CGB VRAM DMA
+	pub dma_stall : u32,				//CPU cycles the CPU is halted by a VRAM DMA, consumed by the emulator
+	pub sgb : Option<Box<Sgb>>,			//Super Game Boy, receives the JOYP writes
+	pub cheats : Cheats,				//Game Genie and GameShark codes
 }
 
 
@@ -20,22 +53,37 @@ impl MemoryBus
 	{
 		MemoryBus
 		{
-			boot_rom : [0; 0x100],
+			boot_rom : [0; 0x900],
 			rom_bank_0 : [0; 0x4000],
 			rom_bank_n : [0; 0x4000],
-			vram : [0; 0x2000],
+			vram : [[0; 0x2000]; 2],
 			ext_ram : [0; 0x2000],
-			work_ram : [0; 0x2000],
+			work_ram : [[0; 0x1000]; 8],
 			sprite_attrib_ram : [0; 0xa0],
 			io_registers : [0; 0x80],
 			high_ram : [0; 0x80],
 			interrupt_enable : 0,
+			cgb_mode : false,
+			vram_bank : 0,
+			wram_bank : 1,
+			bg_palette_ram : [0; 0x40],
+			obj_palette_ram : [0; 0x40],
+			double_speed : false,
+			timer : Timer::init_timer(),
+			apu : Apu::init_apu(),
+			joypad : Joypad::init_joypad(),
+			serial : Serial::init_serial(),
+			infrared : Infrared::init_infrared(),
+			hdma : Hdma::init_hdma(),
+			dma_stall : 0,
+			sgb : None,
+			cheats : Cheats::init_cheats(),
 		}
 	}
 
 	pub fn load_boot_rom(&mut self, boot_rom : Vec<u8>)
 	{
-		for i in 0x0..0x100
+		for i in 0x0..boot_rom.len().min(0x900)
 		{
 			self.boot_rom[i] = boot_rom[i];
 		}
@@ -44,27 +92,57 @@ impl MemoryBus
 	pub fn init_bus_without_bootrom(&mut self)
 	{
 		// ! TO DO
+		self.write_byte(0xFF40, 0x91);	// LCD ON, BG ON
+		self.write_byte(0xFF47, 0xFC);	// BG PALETTE
+		if self.cgb_mode
+		{
+			self.bg_palette_ram = [0xFF; 0x40];	// ALL BG PALETTES WHITE
+			self.obj_palette_ram = [0xFF; 0x40];
+		}
 		self.write_byte(0xFF50, 1);	// DISABLE BOOT ROM
 	}
-	
+
 	pub fn read_byte(&self, address : u16) -> u8
 	{
 		match address
 		{
-			0x0000..=0x3FFF => 
+			0x0000..=0x3FFF =>
 			{
-				if self.read_byte(0xFF50) == 0 && address < 0x100
+				if self.read_byte(0xFF50) == 0 && self.boot_rom_mapped(address)
 				{
 					return self.boot_rom[address as usize];
 				}
-				return self.rom_bank_0[address as usize];
+				return self.cheats.patch_rom(address, self.rom_bank_0[address as usize]);
 			},
-			0x4000..=0x7FFF => self.rom_bank_n[address as usize - 0x4000],
-			0x8000..=0x9FFF => self.vram[address as usize - 0x8000],
+			0x4000..=0x7FFF => self.cheats.patch_rom(address, self.rom_bank_n[address as usize - 0x4000]),
+			0x8000..=0x9FFF if !self.vram_accessible() => 0xFF,
+			0x8000..=0x9FFF => self.vram[self.vram_bank][address as usize - 0x8000],
 			0xA000..=0xBFFF => self.ext_ram[address as usize - 0xA000],
-			0xC000..=0xDFFF => self.work_ram[address as usize - 0xC000],
+			0xC000..=0xCFFF => self.work_ram[0][address as usize - 0xC000],
+			0xD000..=0xDFFF => self.work_ram[self.wram_bank][address as usize - 0xD000],
+			0xE000..=0xFDFF => self.read_byte(address - 0x2000),	// ECHO RAM
+			0xFE00..=0xFE9F if !self.oam_accessible() => 0xFF,
 			0xFE00..=0xFE9F => self.sprite_attrib_ram[address as usize - 0xFE00],
-			0xFF00..=0xFF7F => self.io_registers[address as usize - 0xFF00],
+			0xFF00 =>	// JOYP
+			{
+				let joyp = self.joypad.read_byte();
+				match &self.sgb
+				{
+					Some(sgb) if joyp & 0x30 == 0x30 => 0xF0 | sgb.joypad_id(),
+					_ => joyp,
+				}
+			},
+			SB | SC => self.serial.read_byte(address),
+			0xFF04..=0xFF07 => self.timer.read_byte(address),
+			0xFF10..=0xFF3F | PCM12 | PCM34 => self.apu.read_byte(address),
+			0xFF4D if self.cgb_mode => 0x7E | (self.double_speed as u8) << 7 | self.io_registers[0x4D],	// KEY1
+			0xFF4F if self.cgb_mode => 0xFE | self.vram_bank as u8,	// VBK
+			0xFF51..=0xFF55 if self.cgb_mode => self.hdma.read_byte(address),
+			0xFF69 if self.cgb_mode => self.read_palette(&self.bg_palette_ram, 0x68),	// BCPD
+			0xFF6B if self.cgb_mode => self.read_palette(&self.obj_palette_ram, 0x6A),	// OCPD
+			0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank as u8,	// SVBK
+			RP if self.cgb_mode => self.infrared.read_byte(),
+			0xFF01..=0xFF7F => self.io_registers[address as usize - 0xFF00],
 			0xFF80..=0xFFFE => self.high_ram[address as usize - 0xFF80],
 			0xFFFF => self.interrupt_enable,
 			_ => 0,
@@ -91,17 +169,211 @@ impl MemoryBus
 		{
 			0x0000..=0x3FFF => self.rom_bank_0[address as usize] = value,
 			0x4000..=0x7FFF => self.rom_bank_n[address as usize - 0x4000] = value,
-			0x8000..=0x9FFF => self.vram[address as usize - 0x8000] = value,
+			0x8000..=0x9FFF if !self.vram_accessible() => (),
+			0x8000..=0x9FFF => self.vram[self.vram_bank][address as usize - 0x8000] = value,
 			0xA000..=0xBFFF => self.ext_ram[address as usize - 0xA000] = value,
-			0xC000..=0xDFFF => self.work_ram[address as usize - 0xC000] = value,
+			0xC000..=0xCFFF => self.work_ram[0][address as usize - 0xC000] = value,
+			0xD000..=0xDFFF => self.work_ram[self.wram_bank][address as usize - 0xD000] = value,
+			0xE000..=0xFDFF => self.write_byte(address - 0x2000, value),	// ECHO RAM
+			0xFE00..=0xFE9F if !self.oam_accessible() => (),
 			0xFE00..=0xFE9F => self.sprite_attrib_ram[address as usize - 0xFE00] = value,
-			0xFF00..=0xFF7F => self.io_registers[address as usize - 0xFF00] = value,
+			0xFF41 => self.io_registers[0x41] = (value & 0x78) | (self.io_registers[0x41] & 0x87),	// STAT (MODE AND LYC BITS ARE READ ONLY)
+			0xFF44 => (),	// LY (READ ONLY)
+			0xFF00 =>	// JOYP
+			{
+				if self.joypad.write_byte(value)
+				{
+					self.request_interrupt(INT_JOYPAD);
+				}
+				if let Some(sgb) = &mut self.sgb
+				{
+					sgb.write_joypad(value, &self.vram[0], self.io_registers[0x40]);
+					self.joypad.player = sgb.player as usize;
+				}
+			},
+			SB | SC => self.serial.write_byte(address, value),
+			0xFF04..=0xFF07 =>
+			{
+				let overflow = self.timer.write_byte(address, value);
+				if overflow
+				{
+					self.request_interrupt(INT_TIMER);
+				}
+			},
+			0xFF10..=0xFF3F => self.apu.write_byte(address, value),
+			PCM12 | PCM34 => (),	// READ ONLY
+			0xFF4D if self.cgb_mode => self.io_registers[0x4D] = value & 0x01,	// KEY1 (PREPARE SPEED SWITCH)
+			0xFF4F if self.cgb_mode => self.vram_bank = (value & 0x01) as usize,	// VBK
+			0xFF51..=0xFF55 if self.cgb_mode =>
+			{
+				let blocks = self.hdma.write_byte(address, value);
+				for _ in 0..blocks
+				{
+					self.copy_hdma_block();
+				}
+			},
+			0xFF68 if self.cgb_mode => self.io_registers[0x68] = value & 0xBF,	// BCPS
+			0xFF69 if self.cgb_mode => 	// BCPD
+			{
+				let accessible = self.vram_accessible();
+				MemoryBus::write_palette(&mut self.bg_palette_ram, &mut self.io_registers[0x68], accessible, value);
+			},
+			0xFF6A if self.cgb_mode => self.io_registers[0x6A] = value & 0xBF,	// OCPS
+			0xFF6B if self.cgb_mode => 	// OCPD
+			{
+				let accessible = self.vram_accessible();
+				MemoryBus::write_palette(&mut self.obj_palette_ram, &mut self.io_registers[0x6A], accessible, value);
+			},
+			0xFF70 if self.cgb_mode => self.wram_bank = ((value & 0x07) as usize).max(1),	// SVBK (BANK 0 SELECTS BANK 1)
+			RP if self.cgb_mode => self.infrared.write_byte(value),
+			0xFF01..=0xFF7F => self.io_registers[address as usize - 0xFF00] = value,
 			0xFF80..=0xFFFE => self.high_ram[address as usize - 0xFF80] = value,
 			0xFFFF => self.interrupt_enable = value,
 			_ => (),
 		}
 	}
 
+	pub fn request_interrupt(&mut self, interrupt : u8)
+	{
+		self.io_registers[0x0F] |= interrupt;
+	}
+
+	// MEMORY AND PERIPHERALS (THE BOOT ROM AND THE CHEATS ARE NOT PART OF THE STATE)
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.bytes(&mut self.rom_bank_0);
+		state.bytes(&mut self.rom_bank_n);
+		for bank in self.vram.iter_mut()
+		{
+			state.bytes(bank);
+		}
+		state.bytes(&mut self.ext_ram);
+		for bank in self.work_ram.iter_mut()
+		{
+			state.bytes(bank);
+		}
+		state.bytes(&mut self.sprite_attrib_ram);
+		state.bytes(&mut self.io_registers);
+		state.bytes(&mut self.high_ram);
+		state.u8(&mut self.interrupt_enable);
+		state.index(&mut self.vram_bank, 2);
+		state.index(&mut self.wram_bank, 8);
+		state.bytes(&mut self.bg_palette_ram);
+		state.bytes(&mut self.obj_palette_ram);
+		state.bool(&mut self.double_speed);
+		state.u32(&mut self.dma_stall);
+
+		self.timer.sync_state(state);
+		self.apu.sync_state(state);
+		self.joypad.sync_state(state);
+		self.serial.sync_state(state);
+		self.infrared.sync_state(state);
+		self.hdma.sync_state(state);
+
+		// THE SGB IS THERE WHEN THE MODEL IS SGB, THE MODEL IS RESTORED FIRST
+		let mut has_sgb = self.sgb.is_some();
+		state.bool(&mut has_sgb);
+		match self.sgb.as_mut()
+		{
+			Some(sgb) if has_sgb =>
+			{
+				sgb.sync_state(state);
+				self.joypad.player = sgb.player as usize;
+			},
+			None if !has_sgb => self.joypad.player = 0,
+			_ => state.fail(StateError::BadValue("SGB state of another model".to_string())),
+		}
+	}
+
+	// GAMESHARK CODES, WRITTEN AT EACH VBLANK
+	pub fn apply_ram_cheats(&mut self)
+	{
+		let writes : Vec<(u8, u16, u8)> = self.cheats.ram_writes().collect();
+		for (bank, address, value) in writes
+		{
+			match (bank, address)
+			{
+				// CGB WRAM BANK, WHATEVER BANK IS MAPPED AT 0xD000
+				(0x90..=0x97, 0xD000..=0xDFFF) => self.work_ram[((bank & 0x07) as usize).max(1)][address as usize - 0xD000] = value,
+				_ => self.write_byte(address, value),
+			}
+		}
+	}
+
+	// CALLED BY THE STOP INSTRUCTION WHEN KEY1 BIT 0 IS SET
+	pub fn switch_speed(&mut self)
+	{
+		self.double_speed = !self.double_speed;
+		self.io_registers[0x4D] = 0;
+		self.timer.counter = 0;
+	}
+
+	// CALLED BY THE PPU AT THE START OF EACH HBLANK OF THE VISIBLE LINES
+	pub fn hblank_dma(&mut self)
+	{
+		if self.hdma.active
+		{
+			self.copy_hdma_block();
+			self.hdma.active = self.hdma.blocks != 0;
+		}
+	}
+
+	// COPY 16 BYTES TO VRAM, THE CPU IS HALTED FOR 8 M-CYCLES (16 IN DOUBLE SPEED)
+	fn copy_hdma_block(&mut self)
+	{
+		for _ in 0..0x10
+		{
+			let value = self.read_byte(self.hdma.source);
+			self.vram[self.vram_bank][(self.hdma.destination & 0x1FFF) as usize] = value;
+			self.hdma.source = self.hdma.source.wrapping_add(1);
+			self.hdma.destination = self.hdma.destination.wrapping_add(1);
+		}
+		self.hdma.blocks -= 1;
+		self.dma_stall += 32 << self.double_speed as u32;
+	}
+
+	// THE CGB BOOT ROM IS SPLIT AROUND THE CARTRIDGE HEADER (0x0000-0x00FF AND 0x0200-0x08FF)
+	fn boot_rom_mapped(&self, address : u16) -> bool
+	{
+		address < 0x100 || (self.cgb_mode && (0x200..0x900).contains(&address))
+	}
+
+	// VRAM IS LOCKED FOR THE CPU WHILE THE PPU IS DRAWING (MODE 3)
+	pub fn vram_accessible(&self) -> bool
+	{
+		self.io_registers[0x41] & 0x03 != 3
+	}
+
+	// OAM IS LOCKED FOR THE CPU DURING OAM SCAN AND DRAWING (MODES 2 AND 3)
+	pub fn oam_accessible(&self) -> bool
+	{
+		self.io_registers[0x41] & 0x03 < 2
+	}
+
+	// PALETTE DATA IS ADDRESSED BY THE INDEX IN BCPS/OCPS, LOCKED DURING MODE 3
+	fn read_palette(&self, palette_ram : &[u8; 0x40], spec : usize) -> u8
+	{
+		if !self.vram_accessible()
+		{
+			return 0xFF;
+		}
+		palette_ram[(self.io_registers[spec] & 0x3F) as usize]
+	}
+
+	// WRITING THE DATA REGISTER INCREMENTS THE INDEX IF BIT 7 OF BCPS/OCPS IS SET (EVEN IF THE WRITE IS LOCKED)
+	fn write_palette(palette_ram : &mut [u8; 0x40], spec : &mut u8, accessible : bool, value : u8)
+	{
+		let index = *spec & 0x3F;
+		if accessible
+		{
+			palette_ram[index as usize] = value;
+		}
+		if *spec & 0x80 != 0
+		{
+			*spec = 0x80 | ((index + 1) & 0x3F);
+		}
+	}
+
 	pub fn write_short(&mut self, address : u16, value : u16)
 	{
 		let hl: u8 = ((value >> 8) & 0x00FF) as u8;
@@ -1,4 +1,8 @@
 //Imports
+use std::fmt;
+use std::io;
+use crate::licensee;
+use crate::romdb::{self, KnownRom};
 
 // Constants
 const NINTENDO_LOGO : [u8;48] = 
@@ -10,17 +14,48 @@ const NINTENDO_LOGO : [u8;48] =
 	0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E
 ];
 
-const CART_TYPE : [&str; 35] = 
-[
-	"ROM ONLY", "MBC1", "MBC1+RAM", "MBC1+RAM+BATTERY", "0x04 ???",
-	"MBC2", "MBC2+BATTERY", "0x07 ???", "ROM+RAM 1", "ROM+RAM+BATTERY 1", 
-	"0x0A ???", "MMM01", "MMM01+RAM", "MMM01+RAM+BATTERY", "0x0E ???",
-	"MBC3+TIMER+BATTERY", "MBC3+TIMER+RAM+BATTERY 2", "MBC3", "MBC3+RAM 2", 
-	"MBC3+RAM+BATTERY 2", "0x14 ???", "0x15 ???", "0x16 ???", "0x17 ???",
-	"0x18 ???", "MBC5", "MBC5+RAM", "MBC5+RAM+BATTERY", "MBC5+RUMBLE",
-	"MBC5+RUMBLE+RAM", "MBC5+RUMBLE+RAM+BATTERY", "0x1F ???", "MBC6", "0x21 ???",
-	"MBC7+SENSOR+RUMBLE+RAM+BATTERY",
-];
+const HEADER_END : usize = 0x150;	//The header ends at 0x14F, smaller files cannot be loaded
+pub const MAX_ROM_SIZE : usize = 8 * 1024 * 1024;	//Biggest ROM size of the header, bounds archives and patches
+
+// Why a ROM could not be loaded
+#[derive(Debug)]
+pub enum RomError
+{
+	Io(io::Error),						//The file could not be read
+	Truncated(usize),					//File size, too small to hold the header
+	SizeMismatch { expected : usize, actual : usize },	//Smaller than the ROM size of the header
+	UnsupportedMapper(u8),				//Cartridge type
+	BadHeader(String),
+	Archive(String),					//The .zip or .gz file is damaged
+	NoRomInArchive,						//No .gb or .gbc file in the zip archive
+	Patch(String),						//The IPS/UPS/BPS patch cannot be applied
+}
+
+impl fmt::Display for RomError
+{
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			RomError::Io(err) => write!(f, "{}", err),
+			RomError::Truncated(size) => write!(f, "the file is truncated ({} bytes), a ROM holds at least {} bytes", size, HEADER_END),
+			RomError::SizeMismatch { expected, actual } => write!(f, "the header declares {} KB of ROM but the file only holds {} bytes", expected / 1024, actual),
+			RomError::UnsupportedMapper(cartridge_type) => write!(f, "unsupported cartridge type 0x{:02X} ({}), only cartridges without a mapper can run", cartridge_type, CartridgeType::from_code(*cartridge_type).mapper),
+			RomError::BadHeader(reason) => write!(f, "bad header: {}", reason),
+			RomError::Archive(reason) => write!(f, "cannot extract the ROM: {}", reason),
+			RomError::NoRomInArchive => write!(f, "no .gb or .gbc file in the archive"),
+			RomError::Patch(reason) => write!(f, "cannot apply the patch {}", reason),
+		}
+	}
+}
+
+impl From<io::Error> for RomError
+{
+	fn from(err : io::Error) -> RomError
+	{
+		RomError::Io(err)
+	}
+}
 
 // Cartridge Header struct
 pub struct CartridgeHeader
@@ -29,15 +64,270 @@ pub struct CartridgeHeader
 	pub title : [u8; 11],               //11 ASCII Characters
 	pub manufacturer_code : [u8; 4],    //4 ASCII Characters,     
 	pub cgb_flag : u8,                  //GBC Mode or not
+	pub new_licensee_code : [u8; 2],    //2 ASCII Characters, used when old_licensee_code is 0x33
 	pub sgb_flag : u8,                  //SGB Mode or not
 	pub cartridge_type : u8,            //Cartridge Type (ROM_ONLY, MBC1,..)
 	pub rom_size : u8,                  //Calculated as 32KB << n
 	pub ram_size : u8,                  //(0, 8, 32, 128, 64)KB
+	pub destination_code : u8,          //0x00: Japan, 0x01: Overseas
+	pub old_licensee_code : u8,         //Publisher code (0x33: see new_licensee_code)
 	pub mask_rom_version : u8,          //Version Number of the Game
 	pub header_checksum : u8,           //Checksum of the header
-	pub global_checksum : u16,          //Checksum of the entire cartridge (Not need to be verified)
+	pub global_checksum : u16,          //Checksum of the entire cartridge (Not checked by the hardware)
+}
+
+
+// CGB flag of the header
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CgbSupport
+{
+	None,		// DMG game
+	Enhanced,	// 0x80: also runs on DMG
+	Only,		// 0xC0: CGB only
+}
+
+// Memory bank controller of the cartridge
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapperKind
+{
+	RomOnly,
+	Mbc1,
+	Mbc2,
+	Mmm01,
+	Mbc3,
+	Mbc5,
+	Mbc6,
+	Mbc7,
+	PocketCamera,
+	Tama5,
+	HuC3,
+	HuC1,
+	Unknown(u8),	// Cartridge type code
+}
+
+impl fmt::Display for MapperKind
+{
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			MapperKind::RomOnly => write!(f, "ROM"),
+			MapperKind::Mbc1 => write!(f, "MBC1"),
+			MapperKind::Mbc2 => write!(f, "MBC2"),
+			MapperKind::Mmm01 => write!(f, "MMM01"),
+			MapperKind::Mbc3 => write!(f, "MBC3"),
+			MapperKind::Mbc5 => write!(f, "MBC5"),
+			MapperKind::Mbc6 => write!(f, "MBC6"),
+			MapperKind::Mbc7 => write!(f, "MBC7"),
+			MapperKind::PocketCamera => write!(f, "POCKET CAMERA"),
+			MapperKind::Tama5 => write!(f, "BANDAI TAMA5"),
+			MapperKind::HuC3 => write!(f, "HuC3"),
+			MapperKind::HuC1 => write!(f, "HuC1"),
+			MapperKind::Unknown(code) => write!(f, "UNKNOWN (0x{:02X})", code),
+		}
+	}
+}
+
+// Cartridge type: the mapper and the hardware on the board
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CartridgeType
+{
+	pub mapper : MapperKind,
+	pub ram : bool,						//External RAM (MBC2 has its own)
+	pub battery : bool,					//The RAM (and the clock) is kept when powered off
+	pub rtc : bool,						//MBC3 real time clock
+	pub rumble : bool,					//Rumble motor
 }
 
+impl CartridgeType
+{
+	pub fn from_code(code : u8) -> CartridgeType
+	{
+		let (mapper, ram, battery, rtc, rumble) = match code
+		{
+			0x00 => (MapperKind::RomOnly, false, false, false, false),
+			0x01 => (MapperKind::Mbc1, false, false, false, false),
+			0x02 => (MapperKind::Mbc1, true, false, false, false),
+			0x03 => (MapperKind::Mbc1, true, true, false, false),
+			0x05 => (MapperKind::Mbc2, false, false, false, false),
+			0x06 => (MapperKind::Mbc2, false, true, false, false),
+			0x08 => (MapperKind::RomOnly, true, false, false, false),
+			0x09 => (MapperKind::RomOnly, true, true, false, false),
+			0x0B => (MapperKind::Mmm01, false, false, false, false),
+			0x0C => (MapperKind::Mmm01, true, false, false, false),
+			0x0D => (MapperKind::Mmm01, true, true, false, false),
+			0x0F => (MapperKind::Mbc3, false, true, true, false),
+			0x10 => (MapperKind::Mbc3, true, true, true, false),
+			0x11 => (MapperKind::Mbc3, false, false, false, false),
+			0x12 => (MapperKind::Mbc3, true, false, false, false),
+			0x13 => (MapperKind::Mbc3, true, true, false, false),
+			0x19 => (MapperKind::Mbc5, false, false, false, false),
+			0x1A => (MapperKind::Mbc5, true, false, false, false),
+			0x1B => (MapperKind::Mbc5, true, true, false, false),
+			0x1C => (MapperKind::Mbc5, false, false, false, true),
+			0x1D => (MapperKind::Mbc5, true, false, false, true),
+			0x1E => (MapperKind::Mbc5, true, true, false, true),
+			0x20 => (MapperKind::Mbc6, true, true, false, false),
+			0x22 => (MapperKind::Mbc7, true, true, false, true),
+			0xFC => (MapperKind::PocketCamera, true, true, false, false),
+			0xFD => (MapperKind::Tama5, true, true, true, false),
+			0xFE => (MapperKind::HuC3, true, true, true, false),
+			0xFF => (MapperKind::HuC1, true, true, false, false),
+			_ => (MapperKind::Unknown(code), false, false, false, false),
+		};
+		CartridgeType { mapper, ram, battery, rtc, rumble }
+	}
+}
+
+impl fmt::Display for CartridgeType
+{
+	// SAME NAMES AS THE DOCUMENTATION: MBC3+TIMER+RAM+BATTERY
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+	{
+		write!(f, "{}", self.mapper)?;
+		if self.rtc
+		{
+			write!(f, "+TIMER")?;
+		}
+		if self.rumble
+		{
+			write!(f, "+RUMBLE")?;
+		}
+		if self.ram
+		{
+			write!(f, "+RAM")?;
+		}
+		if self.battery
+		{
+			write!(f, "+BATTERY")?;
+		}
+		Ok(())
+	}
+}
+
+// Region the cartridge was sold in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Destination
+{
+	Japan,
+	Overseas,
+	Unknown(u8),
+}
+
+// Header decoded into typed values
+pub struct CartridgeInfo
+{
+	pub title : String,
+	pub manufacturer_code : Option<String>,	//Only in the 11 character titles of late cartridges
+	pub cgb : CgbSupport,
+	pub sgb : bool,
+	pub cartridge_type : CartridgeType,
+	pub rom_size : Option<usize>,		//In bytes, None for an invalid size code
+	pub ram_size : Option<usize>,		//In bytes, None for an invalid size code
+	pub publisher : Option<&'static str>,	//None for an unknown licensee code
+	pub region : Option<&'static str>,	//From the ROM database
+	pub destination : Destination,
+	pub version : u8,
+}
+
+impl fmt::Display for CartridgeInfo
+{
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+	{
+		writeln!(f, "Title: {}", self.title)?;
+		if let Some(code) = &self.manufacturer_code
+		{
+			writeln!(f, "Manufacturer Code: {}", code)?;
+		}
+		writeln!(f, "CGB: {}", match self.cgb
+		{
+			CgbSupport::None => "No",
+			CgbSupport::Enhanced => "Enhanced",
+			CgbSupport::Only => "Only",
+		})?;
+		writeln!(f, "SGB: {}", if self.sgb { "Yes" } else { "No" })?;
+		writeln!(f, "Cartridge Type: {}", self.cartridge_type)?;
+		match self.rom_size
+		{
+			Some(size) => writeln!(f, "ROM Size: {} KB", size / 1024)?,
+			None => writeln!(f, "ROM Size: Unknown")?,
+		}
+		match self.ram_size
+		{
+			Some(0) => writeln!(f, "RAM Size: None")?,
+			Some(size) if size < 1024 => writeln!(f, "RAM Size: {} bytes", size)?,
+			Some(size) => writeln!(f, "RAM Size: {} KB", size / 1024)?,
+			None => writeln!(f, "RAM Size: Unknown")?,
+		}
+		writeln!(f, "Publisher: {}", self.publisher.unwrap_or("Unknown"))?;
+		if let Some(region) = self.region
+		{
+			writeln!(f, "Region: {}", region)?;
+		}
+		match self.destination
+		{
+			Destination::Japan => writeln!(f, "Destination: Japan")?,
+			Destination::Overseas => writeln!(f, "Destination: Overseas")?,
+			Destination::Unknown(code) => writeln!(f, "Destination: Unknown (0x{:02X})", code)?,
+		}
+		write!(f, "Version: {}", self.version)
+	}
+}
+
+// File size compared to the ROM size of the header
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DumpSize
+{
+	Exact,
+	Overdump(usize),	// Extra bytes at the end of the file
+	Underdump(usize),	// Missing bytes
+	Unknown,			// Invalid ROM size code
+}
+
+// Integrity checks of a ROM
+pub struct ValidationReport
+{
+	pub header_checksum : u8,			//Computed over 0x134-0x14C
+	pub header_checksum_ok : bool,
+	pub global_checksum : u16,			//Computed over the whole file
+	pub global_checksum_ok : bool,
+	pub logo_ok : bool,					//Nintendo logo of the header
+	pub declared_size : Option<usize>,	//ROM size of the header, in bytes
+	pub actual_size : usize,			//File size
+	pub dump : DumpSize,
+}
+
+impl ValidationReport
+{
+	// Good dump: every check passed
+	pub fn is_valid(&self) -> bool
+	{
+		self.header_checksum_ok && self.global_checksum_ok && self.logo_ok && self.dump == DumpSize::Exact
+	}
+}
+
+impl fmt::Display for ValidationReport
+{
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+	{
+		let status = |ok : bool| if ok { "OK" } else { "BAD" };
+		writeln!(f, "Nintendo Logo: {}", status(self.logo_ok))?;
+		writeln!(f, "Header Checksum: {} (0x{:02X})", status(self.header_checksum_ok), self.header_checksum)?;
+		writeln!(f, "Global Checksum: {} (0x{:04X})", status(self.global_checksum_ok), self.global_checksum)?;
+		match self.declared_size
+		{
+			Some(size) => write!(f, "Size: {} bytes, {} declared", self.actual_size, size)?,
+			None => write!(f, "Size: {} bytes", self.actual_size)?,
+		}
+		match self.dump
+		{
+			DumpSize::Exact => Ok(()),
+			DumpSize::Overdump(extra) => write!(f, " (overdump, {} extra bytes)", extra),
+			DumpSize::Underdump(missing) => write!(f, " (underdump, {} bytes missing)", missing),
+			DumpSize::Unknown => write!(f, " (invalid ROM size code)"),
+		}
+	}
+}
 
 //Cartridge struct
 pub struct Cartridge
@@ -46,6 +336,7 @@ pub struct Cartridge
 	pub size : u32,
 	pub data : Vec<u8>,
 	pub header : CartridgeHeader,
+	pub known : Option<&'static KnownRom>,	//Entry of the ROM database, overrides the header
 }
 
 
@@ -60,26 +351,95 @@ impl Cartridge
 			size : 0,
 			data : Vec::new(),
 			header : CartridgeHeader::init_header(),
+			known : None,
 		}
 	}
 	
-	pub fn load_cartridge(&mut self, filename : &str, data : Vec<u8>)
+	pub fn load_cartridge(&mut self, filename : &str, data : Vec<u8>) -> Result<(), RomError>
 	{
+		self.open_cartridge(filename, data)?;	//Fetch the header without checking it
+
+		self.header.validate(self.data.len())?;	//Reject what cannot be run
+
+		println!("--------------------------------------------------------------");
+
+		println!("{}", self.info());	//Print the header
+		
+		println!("{}", self.validation_report());	//Print the checksums and the dump size
+
 		println!("--------------------------------------------------------------");
+		Ok(())
+	}
+
+	// Read the header of a ROM without checking that it can be run
+	pub fn open_cartridge(&mut self, filename : &str, data : Vec<u8>) -> Result<(), RomError>
+	{
+		if data.len() < HEADER_END
+		{
+			return Err(RomError::Truncated(data.len()));
+		}
 
 		self.filename = filename.to_string(); //Copy the filename
 		
+		self.size = data.len() as u32;	//Get the file size
+
 		self.data = data;	//Get the rom data
 		
 		self.get_header();	//Fetch the header from the cartridge
-		
-		self.header.print_header();	//Print the header
-		
-		self.header.check_header_checksum(&self.data);	//Check the header checksum
 
-		println!("--------------------------------------------------------------");
+		self.known = romdb::lookup(&self.data);	//Look for the ROM in the database
+
+		if let Some(rom) = self.known
+		{
+			self.header.apply_known_rom(rom);	//Fix the header of known ROMs
+		}
+		Ok(())
+	}
+
+	// Decoded header, with the canonical title and region of a known ROM
+	pub fn info(&self) -> CartridgeInfo
+	{
+		let mut info = self.header.info();
+		if let Some(rom) = self.known
+		{
+			info.title = rom.title.to_string();
+			info.region = Some(&rom.region);
+			info.sgb = rom.sgb;
+		}
+		info
+	}
+
+	pub fn supports_sgb(&self) -> bool
+	{
+		self.known.map_or(self.header.supports_sgb(), |rom| rom.sgb)
+	}
+
+	// Integrity of the ROM data against its header
+	pub fn validation_report(&self) -> ValidationReport
+	{
+		let header_checksum = header_checksum(&self.data);
+		let global_checksum = global_checksum(&self.data);
+		let declared_size = self.header.rom_bytes();
+		ValidationReport
+		{
+			header_checksum,
+			header_checksum_ok : header_checksum == self.header.header_checksum,
+			global_checksum,
+			global_checksum_ok : global_checksum == self.header.global_checksum,
+			logo_ok : self.header.logo == NINTENDO_LOGO,
+			declared_size,
+			actual_size : self.data.len(),
+			dump : match declared_size
+			{
+				Some(size) if self.data.len() > size => DumpSize::Overdump(self.data.len() - size),
+				Some(size) if self.data.len() < size => DumpSize::Underdump(size - self.data.len()),
+				Some(_) => DumpSize::Exact,
+				None => DumpSize::Unknown,
+			},
+		}
 	}
 	
+	// The data must hold the whole header (checked by load_cartridge)
 	pub fn get_header(&mut self)
 	{
 		//GET LOGO
@@ -92,6 +452,8 @@ impl Cartridge
 		
 		self.header.cgb_flag = self.data[0x143];    //Get the CGB flag
 		
+		self.header.new_licensee_code.copy_from_slice(&self.data[0x144..0x146]);	//Get the new licensee code
+		
 		self.header.sgb_flag = self.data[0x146];	//Get the SGB flag
 		
 		self.header.cartridge_type = self.data[0x147];	//Get the cartridge type
@@ -100,6 +462,10 @@ impl Cartridge
 		
 		self.header.ram_size = self.data[0x149];	//Get the RAM size
 		
+		self.header.destination_code = self.data[0x14A];	//Get the destination code
+		
+		self.header.old_licensee_code = self.data[0x14B];	//Get the old licensee code
+		
 		self.header.mask_rom_version = self.data[0x14C];	//Get the ROM version
 		
 		self.header.header_checksum = self.data[0x14D];	//Get the header checksum
@@ -119,68 +485,172 @@ impl CartridgeHeader
 			title : [0;11],
 			manufacturer_code : [0;4],
 			cgb_flag : 0,
+			new_licensee_code : [0;2],
 			sgb_flag : 0,
 			cartridge_type : 0,
 			rom_size : 0,
 			ram_size : 0,
+			destination_code : 0,
+			old_licensee_code : 0,
 			mask_rom_version : 0,
 			header_checksum : 0,
 			global_checksum : 0,
 		}
 	}
 
-	pub fn check_header_checksum(&mut self, data : &Vec<u8>)
+	pub fn is_cgb(&self) -> bool
 	{
-		let mut x : u8 = 0;
-		
-		for i in 0x134..0x14D
+		self.cgb_flag & 0x80 != 0	//0x80: CGB enhanced, 0xC0: CGB only
+	}
+
+	pub fn supports_sgb(&self) -> bool
+	{
+		self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+	}
+
+	// ROM size in bytes, None for an invalid size code
+	pub fn rom_bytes(&self) -> Option<usize>
+	{
+		match self.rom_size
 		{
-			x = x.wrapping_sub(data[i]).wrapping_sub(1);	
+			0x00..=0x08 => Some(0x8000 << self.rom_size),
+			_ => None,
 		}
-		
-		if x == self.header_checksum
+	}
+
+	// RAM size in bytes, None for an invalid size code
+	pub fn ram_bytes(&self) -> Option<usize>
+	{
+		match self.ram_size
 		{
-			println!("Header Checksum OK");
+			// MBC2 HAS 512 HALF BYTES OF RAM INSIDE THE CONTROLLER, THE HEADER DECLARES NONE
+			_ if self.cartridge_type().mapper == MapperKind::Mbc2 => Some(512),
+			0x00 => Some(0),
+			0x01 => Some(2 * 1024),		//Unused by official cartridges
+			0x02 => Some(8 * 1024),
+			0x03 => Some(32 * 1024),
+			0x04 => Some(128 * 1024),
+			0x05 => Some(64 * 1024),
+			_ => None,
 		}
-		else
+	}
+
+	pub fn cartridge_type(&self) -> CartridgeType
+	{
+		CartridgeType::from_code(self.cartridge_type)
+	}
+
+	pub fn cgb_support(&self) -> CgbSupport
+	{
+		match self.cgb_flag
 		{
-			println!("Header Checksum Error");
-			println!("Header Checksum = {}", x);
+			0xC0 => CgbSupport::Only,
+			flag if flag & 0x80 != 0 => CgbSupport::Enhanced,
+			_ => CgbSupport::None,
 		}
 	}
 
-	pub fn print_header(&mut self)
+	// Cartridge types the emulator can run: no mapper is emulated yet, only 32 KB ROMs
+	// (0x00, 0x08 and 0x09) are mapped
+	pub fn is_supported_type(&self) -> bool
 	{
-		//CHECK IF LOGO IS CORRECT
-		if self.logo == NINTENDO_LOGO
+		self.cartridge_type().mapper == MapperKind::RomOnly
+	}
+
+	// Title area: 16 characters on DMG games, then the CGB flag took the last byte (15 characters)
+	// and late cartridges took 4 more for the manufacturer code (11 characters)
+	pub fn title(&self) -> (String, Option<String>)
+	{
+		let manufacturer_code = self.manufacturer_code.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+		let mut title = self.title.to_vec();
+		let mut code = None;
+		if self.cgb_support() != CgbSupport::None && manufacturer_code
 		{
-			println!("Nintendo Logo found, it is an official ROM !");
+			code = Some(self.manufacturer_code.iter().map(|&c| c as char).collect());
 		}
 		else
 		{
-			println!("Nintendo Logo not found, it is not an official ROM !");
+			title.extend_from_slice(&self.manufacturer_code);
+			if self.cgb_support() == CgbSupport::None
+			{
+				title.push(self.cgb_flag);
+			}
 		}
 
-		println!("Title: {}", String::from_utf8(self.title.to_vec()).unwrap());	//Print the title
+		// PADDED WITH ZEROS, NON ASCII BYTES ARE SHOWN AS '?'
+		let end = title.iter().position(|&c| c == 0).unwrap_or(title.len());
+		let title = title[..end].iter().map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '?' }).collect::<String>();
+		(title.trim_end().to_string(), code)
+	}
 
-		println!("Manufacturer Code: {}", String::from_utf8(self.manufacturer_code.to_vec()).unwrap());	//Print the manufacturer code
-		println!("CGB Flag: {}", self.cgb_flag);	//Print the CGB flag
-		println!("SGB Flag: {}", self.sgb_flag);	//Print the SGB flag
-		println!("Cartridge Type: {}", CART_TYPE[self.cartridge_type as usize]);	//Print the cartridge type
-		println!("ROM Size: {} KB", 32 << self.rom_size);	//Print the ROM size
-		println!("RAM Size: {}", match self.ram_size	{	//Print the RAM size
-			0 => "None",
-			1 => "Unused",
-			2 => "8KB",
-			3 => "32KB",
-			4 => "128KB",
-			5 => "64KB",
-			_ => "Unknown",	
-	});
-		println!("ROM Version: {}", self.mask_rom_version);	//Print the ROM version
-		println!("Header Checksum: {}", self.header_checksum);	//Print the header checksum
-		println!("Global Checksum: {}", self.global_checksum);	//Print the global checksum
+	pub fn info(&self) -> CartridgeInfo
+	{
+		let (title, manufacturer_code) = self.title();
+		CartridgeInfo
+		{
+			title,
+			manufacturer_code,
+			cgb : self.cgb_support(),
+			sgb : self.supports_sgb(),
+			cartridge_type : self.cartridge_type(),
+			rom_size : self.rom_bytes(),
+			ram_size : self.ram_bytes(),
+			publisher : licensee::publisher(self.old_licensee_code, self.new_licensee_code),
+			region : None,
+			destination : match self.destination_code
+			{
+				0x00 => Destination::Japan,
+				0x01 => Destination::Overseas,
+				code => Destination::Unknown(code),
+			},
+			version : self.mask_rom_version,
+		}
+	}
 
-		
+	// Check the size codes and the cartridge type against the file size
+	pub fn validate(&self, file_size : usize) -> Result<(), RomError>
+	{
+		let expected = self.rom_bytes().ok_or(RomError::BadHeader(format!("invalid ROM size code 0x{:02X}", self.rom_size)))?;
+		if self.ram_bytes().is_none()
+		{
+			return Err(RomError::BadHeader(format!("invalid RAM size code 0x{:02X}", self.ram_size)));
+		}
+		if !self.is_supported_type()
+		{
+			return Err(RomError::UnsupportedMapper(self.cartridge_type));
+		}
+		// A BIGGER FILE IS AN OVERDUMP, IT CAN STILL BE RUN
+		if file_size < expected
+		{
+			return Err(RomError::SizeMismatch { expected, actual : file_size });
+		}
+		Ok(())
 	}
-}
\ No newline at end of file
+
+	// Use the mapper and the CGB support of the database instead of the header
+	pub fn apply_known_rom(&mut self, rom : &KnownRom)
+	{
+		self.cartridge_type = rom.cartridge_type;
+		self.cgb_flag = match rom.cgb
+		{
+			CgbSupport::Only => 0xC0,
+			CgbSupport::Enhanced => 0x80,
+			CgbSupport::None if self.cgb_flag & 0x80 != 0 => 0x00,
+			CgbSupport::None => self.cgb_flag,	//Last character of a DMG title
+		};
+	}
+}
+
+// Checked by the boot ROM, which locks up on a mismatch
+pub fn header_checksum(data : &[u8]) -> u8
+{
+	data[0x134..0x14D].iter().fold(0u8, |x, &byte| x.wrapping_sub(byte).wrapping_sub(1))
+}
+
+// Sum of every byte of the ROM except the checksum itself, never checked by the hardware
+pub fn global_checksum(data : &[u8]) -> u16
+{
+	data.iter().enumerate()
+		.filter(|&(i, _)| i != 0x14E && i != 0x14F)
+		.fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
@@ -0,0 +1,199 @@
+use std::fs;
+
+// Cheat codes. The list of a ROM is kept in <rom>.cht, one cheat per line:
+//     on  00A-17B-C49 Infinite lives
+//     off 010138CD    Max money
+// Game Genie codes patch the ROM when it is read, GameShark codes write to RAM at each VBlank.
+
+pub const CHEAT_EXTENSION : &str = "cht";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheatCode
+{
+	// ABC-DEF-GHI: value AB at address FCDE (F XORed with 0xF), only replaces the compare byte GI when given
+	GameGenie { address : u16, value : u8, compare : Option<u8> },
+	// ABCDEFGH: value CD at address GHEF, AB selects the RAM bank (0x8n: cartridge RAM, 0x9n: CGB WRAM)
+	GameShark { bank : u8, address : u16, value : u8 },
+}
+
+impl CheatCode
+{
+	pub fn parse(code : &str) -> Result<CheatCode, String>
+	{
+		let digits = code.chars().filter(|&c| c != '-')
+			.map(|c| c.to_digit(16).map(|d| d as u8).ok_or(format!("invalid character '{}' in {}", c, code)))
+			.collect::<Result<Vec<u8>, String>>()?;
+		let byte = |i : usize| digits[i] << 4 | digits[i + 1];
+		match digits.len()
+		{
+			6 | 9 =>
+			{
+				if digits[5] & 0x08 == 0
+				{
+					let address = (digits[5] ^ 0x0F) as u16;
+					return Err(format!("{}: Game Genie codes patch the ROM (0x0000-0x7FFF), 0x{:X}xxx is outside", code, address));
+				}
+				let address = ((digits[5] ^ 0x0F) as u16) << 12 | (digits[2] as u16) << 8 | (digits[3] as u16) << 4 | digits[4] as u16;
+				let compare = if digits.len() == 9 { Some((digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA) } else { None };
+				Ok(CheatCode::GameGenie { address, value : byte(0), compare })
+			},
+			8 if !code.contains('-') =>
+			{
+				let bank = byte(0);
+				let address = (byte(6) as u16) << 8 | byte(4) as u16;
+				if !matches!(bank, 0x00 | 0x01 | 0x80..=0x87 | 0x90..=0x97)
+				{
+					return Err(format!("{}: unknown GameShark code type 0x{:02X}", code, bank));
+				}
+				if !(0xA000..=0xDFFF).contains(&address)
+				{
+					return Err(format!("{}: GameShark codes write to RAM (0xA000-0xDFFF), 0x{:04X} is outside", code, address));
+				}
+				Ok(CheatCode::GameShark { bank, address, value : byte(2) })
+			},
+			_ => Err(format!("{}: expected a Game Genie code (ABC-DEF or ABC-DEF-GHI) or a GameShark code (8 digits)", code)),
+		}
+	}
+}
+
+pub struct Cheat
+{
+	pub code : String,					//As written in the list
+	pub name : String,
+	pub enabled : bool,
+	pub effect : CheatCode,
+}
+
+pub struct Cheats
+{
+	pub list : Vec<Cheat>,
+	rom_patches : Vec<(u16, u8, Option<u8>)>,	//Enabled Game Genie codes, checked at each ROM read
+}
+
+impl Cheats
+{
+	pub fn init_cheats() -> Cheats
+	{
+		Cheats
+		{
+			list : Vec::new(),
+			rom_patches : Vec::new(),
+		}
+	}
+
+	// Cheat list of a ROM, a missing file gives an empty list
+	pub fn load(path : &str) -> Result<Cheats, String>
+	{
+		let mut cheats = Cheats::init_cheats();
+		let Ok(text) = fs::read_to_string(path) else { return Ok(cheats) };
+		for (number, line) in text.lines().enumerate()
+		{
+			let line = line.split('#').next().unwrap_or("").trim();
+			if line.is_empty()
+			{
+				continue;
+			}
+
+			// on|off CODE NAME, ANY NUMBER OF SPACES BETWEEN THE WORDS
+			let (state, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+			let enabled = match state.to_ascii_lowercase().as_str()
+			{
+				"on" => true,
+				"off" => false,
+				_ => return Err(format!("{}:{}: expected \"on|off CODE name\"", path, number + 1)),
+			};
+			let rest = rest.trim_start();
+			let (code, name) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+			if code.is_empty()
+			{
+				return Err(format!("{}:{}: missing code", path, number + 1));
+			}
+			let name = name.trim();
+			cheats.add(code, name, enabled).map_err(|err| format!("{}:{}: {}", path, number + 1, err))?;
+		}
+		Ok(cheats)
+	}
+
+	pub fn save(&self, path : &str) -> Result<(), String>
+	{
+		let text = self.list.iter()
+			.map(|cheat| format!("{:<3} {} {}", if cheat.enabled { "on" } else { "off" }, cheat.code, cheat.name).trim_end().to_string() + "\n")
+			.collect::<String>();
+		fs::write(path, text).map_err(|e| e.to_string())
+	}
+
+	pub fn add(&mut self, code : &str, name : &str, enabled : bool) -> Result<(), String>
+	{
+		let effect = CheatCode::parse(code)?;
+		self.list.push(Cheat
+		{
+			code : code.to_ascii_uppercase(),
+			name : name.to_string(),
+			enabled,
+			effect,
+		});
+		self.update_patches();
+		Ok(())
+	}
+
+	pub fn set_enabled(&mut self, index : usize, enabled : bool)
+	{
+		if let Some(cheat) = self.list.get_mut(index)
+		{
+			cheat.enabled = enabled;
+			self.update_patches();
+		}
+	}
+
+	fn update_patches(&mut self)
+	{
+		self.rom_patches = self.list.iter().filter(|cheat| cheat.enabled).filter_map(|cheat| match cheat.effect
+		{
+			CheatCode::GameGenie { address, value, compare } => Some((address, value, compare)),
+			_ => None,
+		}).collect();
+	}
+
+	// Byte read from the ROM, replaced by a Game Genie code. The compare byte tells
+	// the banks apart: the code only applies to the bank holding the original byte.
+	pub fn patch_rom(&self, address : u16, original : u8) -> u8
+	{
+		self.rom_patches.iter()
+			.find(|&&(a, _, compare)| a == address && compare.is_none_or(|c| c == original))
+			.map_or(original, |&(_, value, _)| value)
+	}
+
+	// Enabled GameShark codes: (bank, address, value)
+	pub fn ram_writes(&self) -> impl Iterator<Item = (u8, u16, u8)> + '_
+	{
+		self.list.iter().filter(|cheat| cheat.enabled).filter_map(|cheat| match cheat.effect
+		{
+			CheatCode::GameShark { bank, address, value } => Some((bank, address, value)),
+			_ => None,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn save_and_load()
+	{
+		let mut cheats = Cheats::init_cheats();
+		cheats.add("00A-17B-C49", "Infinite lives", true).unwrap();
+		cheats.add("010138CD", "Max  money", false).unwrap();
+		cheats.add("01FF20C0", "", true).unwrap();
+
+		let path = std::env::temp_dir().join(format!("cheats_{}.{}", std::process::id(), CHEAT_EXTENSION));
+		let path = path.to_str().unwrap();
+		cheats.save(path).unwrap();
+		let loaded = Cheats::load(path);
+		let _ = fs::remove_file(path);
+
+		let list = |cheats : &Cheats| cheats.list.iter().map(|c| (c.code.clone(), c.name.clone(), c.enabled)).collect::<Vec<_>>();
+		assert_eq!(list(&loaded.unwrap()), list(&cheats));
+	}
+}
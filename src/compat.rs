@@ -0,0 +1,180 @@
+use crate::cartridge::*;
+use crate::ppu::PPU;
+
+// Colorization palettes used by the CGB boot ROM when a DMG cartridge is inserted.
+// The boot ROM loads one BG palette and two OBJ palettes, the PPU then maps the
+// 4 DMG shades of BGP, OBP0 and OBP1 onto them.
+
+pub type Palette = [[u8; 3]; 4];
+
+pub struct CompatPalette
+{
+	pub combo : &'static str,	//Buttons held while the boot logo is shown
+	pub buttons : u8,			//Same combo in joypad bits: right, left, up, down, A, B
+	pub name : &'static str,
+	pub combination : usize,	//Entry of COMBINATIONS
+}
+
+// The 12 palettes selectable with a button combo during the boot animation
+pub const COMPAT_PALETTES : [CompatPalette; 12] =
+[
+	CompatPalette { combo : "up", buttons : 0x04, name : "brown", combination : 5 },
+	CompatPalette { combo : "up+a", buttons : 0x14, name : "red", combination : 43 },
+	CompatPalette { combo : "up+b", buttons : 0x24, name : "dark-brown", combination : 28 },
+	CompatPalette { combo : "left", buttons : 0x02, name : "blue", combination : 48 },
+	CompatPalette { combo : "left+a", buttons : 0x12, name : "dark-blue", combination : 40 },
+	CompatPalette { combo : "left+b", buttons : 0x22, name : "grayscale", combination : 7 },
+	CompatPalette { combo : "down", buttons : 0x08, name : "pastel", combination : 8 },
+	CompatPalette { combo : "down+a", buttons : 0x18, name : "orange", combination : 3 },
+	CompatPalette { combo : "down+b", buttons : 0x28, name : "yellow", combination : 49 },
+	CompatPalette { combo : "right", buttons : 0x01, name : "green", combination : 1 },
+	CompatPalette { combo : "right+a", buttons : 0x11, name : "dark-green", combination : 0 },
+	CompatPalette { combo : "right+b", buttons : 0x21, name : "inverted", combination : 6 },
+];
+
+// Colors of the boot ROM palettes (RGB555), 4 per palette. The palettes are stored one after
+// the other, some combinations below start in the middle of one.
+const BOOT_COLORS : [u16; 120] =
+[
+	0x7FFF, 0x32BF, 0x00D0, 0x0000,	// 0
+	0x639F, 0x4279, 0x15B0, 0x04CB,	// 1
+	0x7FFF, 0x6E31, 0x454A, 0x0000,	// 2
+	0x7FFF, 0x1BEF, 0x0200, 0x0000,	// 3
+	0x7FFF, 0x421F, 0x1CF2, 0x0000,	// 4
+	0x7FFF, 0x5294, 0x294A, 0x0000,	// 5
+	0x7FFF, 0x03FF, 0x012F, 0x0000,	// 6
+	0x7FFF, 0x03EF, 0x01D6, 0x0000,	// 7
+	0x7FFF, 0x42B5, 0x3DC8, 0x0000,	// 8
+	0x7E74, 0x03FF, 0x0180, 0x0000,	// 9
+	0x67FF, 0x77AC, 0x1A13, 0x2D6B,	// 10
+	0x7ED6, 0x4BFF, 0x2175, 0x0000,	// 11
+	0x53FF, 0x4A5F, 0x7E52, 0x0000,	// 12
+	0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,	// 13
+	0x03ED, 0x7FFF, 0x255F, 0x0000,	// 14
+	0x036A, 0x021F, 0x03FF, 0x7FFF,	// 15
+	0x7FFF, 0x01DF, 0x0112, 0x0000,	// 16
+	0x231F, 0x035F, 0x00F2, 0x0009,	// 17
+	0x7FFF, 0x03EA, 0x011F, 0x0000,	// 18
+	0x299F, 0x001A, 0x000C, 0x0000,	// 19
+	0x7FFF, 0x027F, 0x001F, 0x0000,	// 20
+	0x7FFF, 0x03E0, 0x0206, 0x0120,	// 21
+	0x7FFF, 0x7EEB, 0x001F, 0x7C00,	// 22
+	0x7FFF, 0x3FFF, 0x7E00, 0x001F,	// 23
+	0x7FFF, 0x03FF, 0x001F, 0x0000,	// 24
+	0x03FF, 0x001F, 0x000C, 0x0000,	// 25
+	0x7FFF, 0x033F, 0x0193, 0x0000,	// 26
+	0x0000, 0x4200, 0x037F, 0x7FFF,	// 27
+	0x7FFF, 0x7E8C, 0x7C00, 0x0000,	// 28
+	0x7FFF, 0x1BEF, 0x6180, 0x0000,	// 29
+];
+
+// Combinations of the boot ROM: offsets in BOOT_COLORS of the BG, OBJ0 and OBJ1 palettes
+const COMBINATIONS : [(usize, usize, usize); 51] =
+[
+	(116, 16, 16), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0),	// 0-5
+	(108, 108, 108), (20, 20, 20), (48, 48, 48), (104, 104, 104), (32, 64, 32), (112, 16, 112),	// 6-11
+	(8, 16, 8), (16, 12, 16), (116, 16, 116), (112, 112, 16), (8, 8, 68), (32, 64, 64),	// 12-17
+	(28, 16, 16), (72, 16, 16), (80, 16, 16), (36, 76, 76), (44, 15, 15), (8, 68, 68),	// 18-23
+	(8, 16, 16), (12, 16, 16), (0, 112, 112), (0, 12, 12), (4, 0, 0), (72, 72, 88),	// 24-29
+	(80, 80, 88), (96, 96, 88), (32, 64, 88), (52, 68, 16), (56, 111, 0), (60, 111, 16),	// 30-35
+	(36, 76, 88), (40, 64, 112), (112, 16, 92), (8, 68, 88), (8, 16, 0), (12, 16, 112),	// 36-41
+	(0, 112, 12), (16, 12, 112), (16, 84, 112), (0, 12, 112), (112, 100, 12), (32, 0, 112),	// 42-47
+	(112, 16, 12), (24, 112, 12), (116, 16, 112),	// 48-50
+];
+
+// Title checksums of the Nintendo games known by the boot ROM. A checksum found after
+// FIRST_AMBIGUOUS is shared by several games, told apart by the 4th letter of the title.
+const TITLE_CHECKSUMS : [u8; 79] =
+[
+	0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+	0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+	0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+	0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+	0xE8, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+const FIRST_AMBIGUOUS : usize = 65;
+
+// 4th title letters of the ambiguous checksums, in rows of 14 following TITLE_CHECKSUMS[FIRST_AMBIGUOUS..]
+const FOURTH_LETTERS : &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Combination of each title checksum, then of each 4th letter
+const TITLE_COMBINATIONS : [u8; 94] =
+[
+	0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20,
+	5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45,
+	36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25,
+	42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+	46, 6, 27, 0, 0, 43, 0, 0, 0, 0, 0, 28, 49, 0,
+];
+
+// Combination used for non-Nintendo cartridges and unknown titles
+const DEFAULT_COMBINATION : usize = 0;
+
+// Find a palette by button combo ("left+a") or name ("dark-blue")
+pub fn find_palette(combo_or_name : &str) -> Option<usize>
+{
+	let key = combo_or_name.to_ascii_lowercase();
+	COMPAT_PALETTES.iter().position(|p| p.combo == key || p.name == key)
+}
+
+// BG, OBJ0 and OBJ1 palettes picked by the boot ROM when no button is held
+pub fn palettes_for_header(header : &CartridgeHeader) -> [Palette; 3]
+{
+	combination_palettes(combination_for_header(header))
+}
+
+// Palette of the buttons held during the boot animation (joypad bits, Select and Start are ignored)
+pub fn palette_for_buttons(pressed : u8) -> Option<usize>
+{
+	COMPAT_PALETTES.iter().position(|p| p.buttons == pressed & 0x3F)
+}
+
+// BG, OBJ0 and OBJ1 palettes of a button combo
+pub fn compat_palettes(index : usize) -> [Palette; 3]
+{
+	combination_palettes(COMPAT_PALETTES[index].combination)
+}
+
+fn combination_palettes(combination : usize) -> [Palette; 3]
+{
+	let (bg, obj0, obj1) = COMBINATIONS[combination];
+	[boot_palette(bg), boot_palette(obj0), boot_palette(obj1)]
+}
+
+fn combination_for_header(header : &CartridgeHeader) -> usize
+{
+	// ONLY NINTENDO PUBLISHED GAMES ARE LOOKED UP
+	let nintendo = header.old_licensee_code == 0x01
+		|| (header.old_licensee_code == 0x33 && &header.new_licensee_code == b"01");
+	if !nintendo
+	{
+		return DEFAULT_COMBINATION;
+	}
+
+	// SUM OF THE 16 BYTES AT 0x134-0x143, THE FIRST MATCH IN THE TABLE WINS
+	let checksum = header.title.iter()
+		.chain(header.manufacturer_code.iter())
+		.fold(header.cgb_flag, |sum, &byte| sum.wrapping_add(byte));
+	let Some(index) = TITLE_CHECKSUMS.iter().position(|&sum| sum == checksum) else { return DEFAULT_COMBINATION };
+	if index < FIRST_AMBIGUOUS
+	{
+		return TITLE_COMBINATIONS[index] as usize;
+	}
+
+	// AMBIGUOUS CHECKSUM: THE 4TH LETTER IS LOOKED UP IN EACH ROW OF LETTERS
+	let fourth_letter = header.title[3];
+	(index - FIRST_AMBIGUOUS..FOURTH_LETTERS.len()).step_by(TITLE_CHECKSUMS.len() - FIRST_AMBIGUOUS)
+		.find(|&letter| FOURTH_LETTERS[letter] == fourth_letter)
+		.map_or(DEFAULT_COMBINATION, |letter| TITLE_COMBINATIONS[FIRST_AMBIGUOUS + letter] as usize)
+}
+
+// 4 colors of BOOT_COLORS from an offset, RGB555 converted to RGB888
+fn boot_palette(offset : usize) -> Palette
+{
+	let mut palette = [[0; 3]; 4];
+	for (color, &value) in palette.iter_mut().zip(BOOT_COLORS[offset..offset + 4].iter())
+	{
+		*color = PPU::rgb555_to_rgb(value);
+	}
+	palette
+}
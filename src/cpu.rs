@@ -1,6 +1,7 @@
 use crate::register::*;
 use crate::bus::*;
 use std::process;
+use crate::savestate::StateIo;
 
 pub struct Cpu
 {
@@ -19,6 +20,12 @@ impl Cpu
 		}
 	}
 
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		self.reg.sync_state(state);
+		state.bool(&mut self.ime);
+	}
+
 	pub fn get_opcode(&mut self, mem_bus : &MemoryBus) -> u8
 	{
 		let opcode = mem_bus.read_byte(self.reg.program_counter);
@@ -76,6 +83,16 @@ impl Cpu
 				self.reg.f.set_half_carry_flag(false);
 				4
 			}
+			0x10 =>	// STOP
+			{
+				self.reg.program_counter += 1;
+				if mem_bus.cgb_mode && mem_bus.io_registers[0x4D] & 0x01 != 0
+				{
+					mem_bus.switch_speed();	// CGB SPEED SWITCH
+					return 8200;
+				}
+				4
+			},
 			0x11 => // LD DE, d16
 			{ 
 				self.reg.set_de(nn);
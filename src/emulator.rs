@@ -3,15 +3,36 @@ use crate::bus::*;
 use crate::cpu::*;
 use crate::register::*;
 use crate::ppu::*;
+use crate::compat;
+use crate::sgb::*;
+use crate::joypad::Button;
+
+use crate::archive;
+use crate::patch;
+use crate::savestate::{StateIo, StateHeader, StateError, STATE_VERSION};
 
 use std::{fs::{metadata, File}, io::Read};
 
+pub const CYCLES_PER_FRAME : u32 = 70224;	// (CLOCK SPEED / REFRESH RATE)
+
+// Hardware model being emulated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model
+{
+	Dmg = 0,	// Original Game Boy
+	Cgb = 1,	// Game Boy Color (DMG cartridges run in compatibility mode)
+	Sgb = 2,	// Super Game Boy (256x224 output with border)
+}
+
 pub struct Emulator
 {
+	pub model : Model,
 	pub cart : Cartridge,
 	pub mem_bus : MemoryBus,
 	pub cpu: Cpu,
 	pub ppu: PPU,
+	pub compat_palette : Option<usize>,	// Palette chosen with a button combo for DMG cartridges on CGB
+	boot_combo_pending : bool,			// The buttons held are read when the boot ROM is unmapped
 	// ! TO DO
 }
 
@@ -22,19 +43,103 @@ impl Emulator
 	{
 		Emulator
 		{
+			model : Model::Dmg,
 			cart : Cartridge::init_cartridge(),
 			mem_bus : MemoryBus::init_bus(),
 			cpu : Cpu::init_cpu(),
 			ppu : PPU::init_ppu(),
+			compat_palette : None,
+			boot_combo_pending : false,
 		}
 	}
 
 	pub fn init_emulator_without_bootrom(&mut self)
 	{
 		self.cpu.reg = Register::init_register_without_bootrom();
+		if self.model == Model::Cgb
+		{
+			self.cpu.reg.a = 0x11;	// CGB IDENTIFIES ITSELF WITH A = 0x11 AFTER BOOT
+		}
+		if self.model == Model::Sgb
+		{
+			self.cpu.reg.f.set_value(0x00);	// SGB BOOT ROM STATE
+			self.cpu.reg.set_bc(0x0014);
+			self.cpu.reg.set_de(0x0000);
+			self.cpu.reg.set_hl(0xC060);
+		}
 		self.mem_bus.init_bus_without_bootrom();
 	}
 
+	pub fn set_model(&mut self, model : Model)
+	{
+		self.model = model;
+
+		// A DMG CARTRIDGE ON CGB HARDWARE RUNS IN DMG COMPATIBILITY MODE, COLORIZED BY THE BOOT ROM
+		self.mem_bus.cgb_mode = model == Model::Cgb && self.cart.header.is_cgb();
+		self.mem_bus.apu.cgb = model == Model::Cgb;
+		self.mem_bus.serial.cgb = self.mem_bus.cgb_mode;
+		self.ppu.dmg_palettes = [DMG_SHADES; 3];
+		if model == Model::Cgb && !self.mem_bus.cgb_mode
+		{
+			self.ppu.dmg_palettes = match self.compat_palette
+			{
+				Some(index) => compat::compat_palettes(index),
+				None => compat::palettes_for_header(&self.cart.header),
+			};
+		}
+		self.boot_combo_pending = model == Model::Cgb && !self.mem_bus.cgb_mode && self.compat_palette.is_none();
+
+		// THE SGB ONLY ACCEPTS PACKETS FROM CARTRIDGES DECLARING SGB SUPPORT
+		self.mem_bus.sgb = None;
+		self.mem_bus.joypad.player = 0;
+		if model == Model::Sgb
+		{
+			self.mem_bus.sgb = Some(Box::new(Sgb::init_sgb(self.cart.supports_sgb())));
+		}
+	}
+
+	// SIZE OF THE IMAGE RETURNED BY get_framebuffer
+	pub fn screen_size(&self) -> (usize, usize)
+	{
+		match self.model
+		{
+			Model::Sgb => (SGB_WIDTH, SGB_HEIGHT),
+			_ => (160, 144),
+		}
+	}
+
+	// RGBA IMAGE OF THE LAST FRAME (WITH THE BORDER ON SGB)
+	pub fn get_framebuffer(&mut self) -> &[u8]
+	{
+		match &mut self.mem_bus.sgb
+		{
+			Some(sgb) => sgb.render(self.ppu.get_shades()),
+			None => self.ppu.get_framebuffer(),
+		}
+	}
+
+	// SAME AS HOLDING A BUTTON COMBO DURING THE CGB BOOT ANIMATION
+	pub fn set_compat_palette(&mut self, index : usize)
+	{
+		self.compat_palette = Some(index);
+		self.set_model(self.model);
+	}
+
+	// PRESS OR RELEASE A JOYPAD BUTTON, player IS 0 EXCEPT IN SGB MULTIPLAYER MODE
+	pub fn set_button(&mut self, player : usize, button : Button, pressed : bool)
+	{
+		if self.mem_bus.joypad.set_button(player, button, pressed)
+		{
+			self.mem_bus.request_interrupt(INT_JOYPAD);
+		}
+	}
+
+	// NUMBER OF CONTROLLERS READ BY THE GAME, MORE THAN 1 AFTER AN SGB MLT_REQ
+	pub fn players(&self) -> usize
+	{
+		self.mem_bus.sgb.as_ref().map_or(1, |sgb| sgb.players as usize)
+	}
+
 	pub fn load_boot_rom(&mut self, filename : &str) -> bool
 	{
 		// READ BOOT ROM
@@ -55,35 +160,240 @@ impl Emulator
 		return true;
 	}
 	
-	pub fn load_rom(&mut self, filename : &str)
+	// LOAD A ROM FILE OR A ROM FROM A .zip/.gz ARCHIVE (THE FIRST .gb/.gbc ENTRY UNLESS ONE IS NAMED)
+	pub fn load_rom(&mut self, filename : &str, entry : Option<&str>) -> Result<(), RomError>
 	{
 		//READ ROM FILE
-		let mut f = File::open(filename).expect("no file found");
-		let metadata = metadata(filename).expect("unable to read metadata");
-		let mut buffer = vec![0; metadata.len() as usize];
-		f.read(&mut buffer).expect("buffer overflow");
+		let mut buffer = archive::read_rom(filename, entry)?;
+
+		//APPLY THE PATCH FOUND NEXT TO THE ROM, IN MEMORY ONLY
+		if let Some(patch) = patch::apply_patch_for(filename, &mut buffer)?
+		{
+			println!("Patch applied: {}", patch);
+		}
 
-		//LOAD THE ROM DATA
-		self.cart.load_cartridge(filename, buffer);
+		//LOAD THE ROM DATA, NAMED AFTER THE ARCHIVE (SAVE FILES USE ITS BASE NAME)
+		self.cart.load_cartridge(filename, buffer)?;
+
+		//SELECT THE MODEL RECOMMENDED BY THE ROM DATABASE, OR FROM THE CGB FLAG OF THE HEADER
+		let model = if self.cart.header.is_cgb() { Model::Cgb } else { Model::Dmg };
+		self.set_model(self.cart.known.map_or(model, |rom| rom.model));
 
 		//FOR NOW NO MBC, COPY THE 32KB ROM INTO THE MEMORY BUS
-		for i in 0x0100..0x7FFF
+		for (i, &byte) in self.cart.data.iter().take(0x8000).enumerate()
 		{
-			self.mem_bus.write_byte(i, self.cart.data[(i - 0x100) as usize]); 
+			self.mem_bus.write_byte(i as u16, byte);
 		}
+		Ok(())
+	}
+
+	// SAVE STATE OF THE WHOLE MACHINE, SEE savestate.rs FOR THE FORMAT
+	pub fn save_state(&mut self) -> Vec<u8>
+	{
+		self.write_state(STATE_VERSION)
 	}
 
+	// SAVE STATE IN A FORMAT VERSION, THE FIELDS ADDED BY LATER VERSIONS ARE LEFT OUT
+	fn write_state(&mut self, version : u16) -> Vec<u8>
+	{
+		let mut state = StateIo::init_saving();
+		state.version = version;
+		let mut header = StateHeader::init_header();
+		header.version = version;
+		header.model = self.model as u8;
+		header.rom_hash = self.rom_hash();
+		state.header(&mut header);
+		self.sync_state(&mut state);
+		state.into_data()
+	}
+
+	// RESTORE A SAVE STATE OF THE SAME ROM, THE EMULATOR IS UNCHANGED WHEN IT FAILS
+	pub fn load_state(&mut self, data : &[u8]) -> Result<(), StateError>
+	{
+		let backup = self.save_state();
+		let model = self.model;
+		let result = self.read_state(data);
+		if result.is_err()
+		{
+			self.set_model(model);
+			let _ = self.read_state(&backup);
+		}
+		result
+	}
+
+	fn read_state(&mut self, data : &[u8]) -> Result<(), StateError>
+	{
+		let mut state = StateIo::init_loading(data);
+		let mut header = StateHeader::init_header();
+		state.header(&mut header);
+		if state.has_error()
+		{
+			return state.finish();
+		}
+		if header.rom_hash != self.rom_hash()
+		{
+			return Err(StateError::WrongRom);
+		}
+
+		// THE MODEL OF THE STATE IS RESTORED FIRST, IT SETS WHICH HARDWARE IS THERE
+		let model = match header.model
+		{
+			0 => Model::Dmg,
+			1 => Model::Cgb,
+			2 => Model::Sgb,
+			model => return Err(StateError::BadValue(format!("unknown model {}", model))),
+		};
+		if model != self.model
+		{
+			self.set_model(model);
+		}
+		self.sync_state(&mut state);
+		self.boot_combo_pending = false;
+		state.finish()
+	}
+
+	fn sync_state(&mut self, state : &mut StateIo)
+	{
+		self.cpu.sync_state(state);
+		self.mem_bus.sync_state(state);
+		self.ppu.sync_state(state);
+	}
+
+	// SHA-1 OF THE ROM (AFTER PATCHING), SAVE STATES ONLY APPLY TO THE SAME ROM
+	pub fn rom_hash(&self) -> [u8; 20]
+	{
+		sha1_smol::Sha1::from(&self.cart.data).digest().bytes()
+	}
+
+	// RUN ONE CPU INSTRUCTION, RETURNS THE ELAPSED CYCLES AT THE NORMAL 4 MHZ CLOCK
 	pub fn emulation_cycle(&mut self) -> u32
 	{
 		// ! TO DO
-		// ! CPU STEP
-		let cycles = self.cpu.step(&mut self.mem_bus);
+		// ! CPU STEP (CPU CLOCK, 8 MHZ IN DOUBLE SPEED)
+		let mut cpu_cycles = self.cpu.step(&mut self.mem_bus);
+		cpu_cycles += std::mem::take(&mut self.mem_bus.dma_stall);	// CPU HALTED BY A VRAM DMA
+
+		// THE CGB BOOT ROM READS THE BUTTON COMBO BEFORE HANDING OVER TO THE CARTRIDGE
+		if self.boot_combo_pending && self.mem_bus.read_byte(0xFF50) != 0
+		{
+			self.boot_combo_pending = false;
+			if let Some(index) = compat::palette_for_buttons(self.mem_bus.joypad.pressed())
+			{
+				self.ppu.dmg_palettes = compat::compat_palettes(index);
+			}
+		}
+
+		// TIMER STEP (CPU CLOCK)
+		let div_before = self.mem_bus.timer.counter;
+		if self.mem_bus.timer.step(cpu_cycles)
+		{
+			self.mem_bus.request_interrupt(INT_TIMER);
+		}
+
+		// SERIAL STEP (CPU CLOCK)
+		if self.mem_bus.serial.step(cpu_cycles)
+		{
+			self.mem_bus.request_interrupt(INT_SERIAL);
+		}
+
+		// INFRARED STEP (CPU CLOCK, TIMESTAMPS OF THE LIGHT SIGNAL)
+		self.mem_bus.infrared.step(cpu_cycles);
+
+		// PPU AND APU ARE NOT AFFECTED BY DOUBLE SPEED
+		let cycles = if self.mem_bus.double_speed { cpu_cycles / 2 } else { cpu_cycles };
 
 		// ! PPU STEP
-		// ! APU STEP
+		let vblank = self.ppu.in_vblank();
+		self.ppu.step(cycles, &mut self.mem_bus);
+
+		// GAMESHARK CODES ARE APPLIED WHEN VBLANK STARTS
+		if !vblank && self.ppu.in_vblank()
+		{
+			self.mem_bus.apply_ram_cheats();
+		}
+
+		// ! APU STEP (FRAME SEQUENCER CLOCKED BY DIV)
+		let div = self.mem_bus.timer.counter;
+		let double_speed = self.mem_bus.double_speed;
+		self.mem_bus.apu.step(cycles, div_before, div, double_speed);
 
 		return cycles;
 	}
+
+	// Run the emulation for the duration of one LCD frame
+	pub fn run_frame(&mut self)
+	{
+		let mut cycles = 0;
+		while cycles < CYCLES_PER_FRAME
+		{
+			cycles += self.emulation_cycle();
+		}
+	}
 }
 
 
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn tetris(model : Model) -> Emulator
+	{
+		let mut emulator = Emulator::init_emulator();
+		emulator.load_rom("roms/tetris.gb", None).unwrap();
+		emulator.set_model(model);
+		emulator.load_boot_rom("roms/dmg_boot.bin");
+		emulator
+	}
+
+	#[test]
+	fn save_and_load_state()
+	{
+		let mut emulator = tetris(Model::Dmg);
+		for _ in 0..1000
+		{
+			emulator.emulation_cycle();
+		}
+		let saved = emulator.save_state();
+		for _ in 0..1000
+		{
+			emulator.emulation_cycle();
+		}
+		assert!(emulator.save_state() != saved);
+
+		emulator.load_state(&saved).unwrap();
+		assert_eq!(emulator.save_state(), saved);
+	}
+
+	#[test]
+	fn load_state_of_version_1()
+	{
+		// THE PALETTES OF THE LEFT BUTTON ARE NOT IN A VERSION 1 STATE
+		let mut emulator = tetris(Model::Cgb);
+		emulator.ppu.dmg_palettes = compat::compat_palettes(3);
+		let old = emulator.write_state(1);
+		assert_eq!(old[4..6], [1, 0]);
+
+		let mut other = tetris(Model::Cgb);
+		let palettes = other.ppu.dmg_palettes;
+		other.load_state(&old).unwrap();
+		assert_eq!(other.ppu.dmg_palettes, palettes);
+
+		// THEY ARE IN THE CURRENT VERSION
+		other.load_state(&emulator.save_state()).unwrap();
+		assert_eq!(other.ppu.dmg_palettes, compat::compat_palettes(3));
+	}
+
+	#[test]
+	fn bad_value_in_state()
+	{
+		let mut emulator = tetris(Model::Dmg);
+		let mut state = emulator.save_state();
+
+		// THE LAST SHADE OF THE FRAME, FOLLOWED BY THE PALETTES
+		let shades_end = state.len() - 3 * 4 * 3;
+		state[shades_end - 1] = 4;
+		assert!(matches!(emulator.load_state(&state), Err(StateError::BadValue(_))));
+	}
+}
@@ -0,0 +1,206 @@
+use std::fs;
+use gilrs::{Axis, Gilrs};
+use crate::joypad::Button;
+use crate::keymap::{bind, Action, Hotkey};
+
+// Gamepad bindings. The config file holds binding profiles, a profile applies to the
+// controllers whose name contains its header ([default] for the others):
+//     [8BitDo]
+//     a = south
+//     b = west
+//     deadzone = 0.4
+//     fast_forward = righttrigger2
+// Like the keyboard, an action listed in a profile loses its default buttons.
+
+pub const DEFAULT_GAMEPAD_FILE : &str = "gamepads.cfg";
+const DEFAULT_DEADZONE : f32 = 0.3;
+
+// Button names accepted in the config file
+const BUTTON_NAMES : [(&str, gilrs::Button); 19] =
+[
+	("south", gilrs::Button::South), ("east", gilrs::Button::East),
+	("north", gilrs::Button::North), ("west", gilrs::Button::West),
+	("c", gilrs::Button::C), ("z", gilrs::Button::Z),
+	("lefttrigger", gilrs::Button::LeftTrigger), ("lefttrigger2", gilrs::Button::LeftTrigger2),
+	("righttrigger", gilrs::Button::RightTrigger), ("righttrigger2", gilrs::Button::RightTrigger2),
+	("select", gilrs::Button::Select), ("start", gilrs::Button::Start), ("mode", gilrs::Button::Mode),
+	("leftthumb", gilrs::Button::LeftThumb), ("rightthumb", gilrs::Button::RightThumb),
+	("dpadup", gilrs::Button::DPadUp), ("dpaddown", gilrs::Button::DPadDown),
+	("dpadleft", gilrs::Button::DPadLeft), ("dpadright", gilrs::Button::DPadRight),
+];
+
+fn button_from_name(name : &str) -> Option<gilrs::Button>
+{
+	let name = name.to_ascii_lowercase();
+	BUTTON_NAMES.iter().find(|(button_name, _)| *button_name == name).map(|&(_, button)| button)
+}
+
+pub struct GamepadProfile
+{
+	pub name : String,					//Matched against the controller names, empty for the default profile
+	pub bindings : Vec<(gilrs::Button, Action)>,
+	pub deadzone : f32,					//Left stick values below it are ignored
+}
+
+impl GamepadProfile
+{
+	// Buttons at the same place as on the Game Boy: east = A, south = B
+	pub fn init_profile(name : &str) -> GamepadProfile
+	{
+		GamepadProfile
+		{
+			name : name.to_string(),
+			bindings : vec![
+				(gilrs::Button::DPadRight, Action::Joypad(Button::Right)),
+				(gilrs::Button::DPadLeft, Action::Joypad(Button::Left)),
+				(gilrs::Button::DPadUp, Action::Joypad(Button::Up)),
+				(gilrs::Button::DPadDown, Action::Joypad(Button::Down)),
+				(gilrs::Button::East, Action::Joypad(Button::A)),
+				(gilrs::Button::South, Action::Joypad(Button::B)),
+				(gilrs::Button::Select, Action::Joypad(Button::Select)),
+				(gilrs::Button::Start, Action::Joypad(Button::Start)),
+				(gilrs::Button::LeftTrigger, Action::Hotkey(Hotkey::SaveState)),
+				(gilrs::Button::LeftTrigger2, Action::Hotkey(Hotkey::LoadState)),
+				(gilrs::Button::RightTrigger, Action::Hotkey(Hotkey::FastForward)),
+				(gilrs::Button::Mode, Action::Hotkey(Hotkey::Menu)),
+			],
+			deadzone : DEFAULT_DEADZONE,
+		}
+	}
+}
+
+pub struct Gamepads
+{
+	gilrs : Gilrs,
+	profiles : Vec<GamepadProfile>,		//The default profile is the last one
+}
+
+impl Gamepads
+{
+	// None when gamepads are not supported on this system
+	pub fn init_gamepads(profiles : Vec<GamepadProfile>) -> Option<Gamepads>
+	{
+		let gilrs = Gilrs::new().ok()?;
+		Some(Gamepads
+		{
+			gilrs,
+			profiles,
+		})
+	}
+
+	// Profiles from the config file, a missing file gives the default profile only
+	pub fn load_profiles(path : &str) -> Result<Vec<GamepadProfile>, String>
+	{
+		let mut profiles = vec![GamepadProfile::init_profile("")];
+		let text = match fs::read_to_string(path)
+		{
+			Ok(text) => text,
+			Err(_) => return Ok(profiles),
+		};
+
+		let mut overridden = Vec::new();
+		for (number, line) in text.lines().enumerate()
+		{
+			let line = line.split('#').next().unwrap_or("").trim();
+			if line.is_empty()
+			{
+				continue;
+			}
+
+			// [PROFILE NAME]
+			if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']'))
+			{
+				let name = if name.trim().eq_ignore_ascii_case("default") { "" } else { name.trim() };
+				profiles.retain(|p| p.name != name);
+				profiles.insert(0, GamepadProfile::init_profile(name));
+				overridden.clear();
+				continue;
+			}
+
+			let (action_name, value) = line.split_once('=')
+				.ok_or(format!("{}:{}: expected \"action = button\"", path, number + 1))?;
+			let (action_name, value) = (action_name.trim(), value.trim());
+			let profile = &mut profiles[0];
+			if action_name.eq_ignore_ascii_case("deadzone")
+			{
+				profile.deadzone = value.parse::<f32>().ok().filter(|d| (0.0..1.0).contains(d))
+					.ok_or(format!("{}:{}: the deadzone must be between 0.0 and 1.0", path, number + 1))?;
+				continue;
+			}
+
+			let action = Action::from_name(action_name)
+				.ok_or(format!("{}:{}: unknown action \"{}\"", path, number + 1, action_name))?;
+			let button = button_from_name(value)
+				.ok_or(format!("{}:{}: unknown gamepad button \"{}\"", path, number + 1, value))?;
+			bind(&mut profile.bindings, &mut overridden, button, action);
+		}
+
+		// THE DEFAULT PROFILE MATCHES EVERY CONTROLLER, IT IS TRIED LAST
+		profiles.sort_by_key(|p| p.name.is_empty());
+		Ok(profiles)
+	}
+
+	fn profile(&self, controller : &str) -> &GamepadProfile
+	{
+		self.profiles.iter()
+			.find(|p| controller.to_ascii_lowercase().contains(&p.name.to_ascii_lowercase()))
+			.unwrap_or(&self.profiles[self.profiles.len() - 1])
+	}
+
+	// Process the controller events, returns the hotkeys pressed since the last poll
+	pub fn poll(&mut self) -> Vec<Hotkey>
+	{
+		let mut hotkeys = Vec::new();
+		while let Some(event) = self.gilrs.next_event()
+		{
+			match event.event
+			{
+				gilrs::EventType::Connected => println!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name()),
+				gilrs::EventType::ButtonPressed(button, _) =>
+				{
+					let profile = self.profile(self.gilrs.gamepad(event.id).name());
+					for &(_, action) in profile.bindings.iter().filter(|(b, _)| *b == button)
+					{
+						if let Action::Hotkey(hotkey) = action
+						{
+							hotkeys.push(hotkey);
+						}
+					}
+				},
+				_ => (),
+			}
+		}
+		hotkeys
+	}
+
+	// Whether an action is held on any connected controller
+	pub fn is_down(&self, action : Action) -> bool
+	{
+		self.gilrs.gamepads().any(|(_, gamepad)| self.is_held(gamepad, action))
+	}
+
+	// Whether an action is held on the nth connected controller (SGB multiplayer)
+	pub fn is_down_on(&self, controller : usize, action : Action) -> bool
+	{
+		self.gilrs.gamepads().nth(controller).is_some_and(|(_, gamepad)| self.is_held(gamepad, action))
+	}
+
+	fn is_held(&self, gamepad : gilrs::Gamepad, action : Action) -> bool
+	{
+		let profile = self.profile(gamepad.name());
+		let pressed = profile.bindings.iter().any(|&(button, a)| a == action && gamepad.is_pressed(button));
+
+		// THE LEFT STICK ALSO MOVES THE D-PAD, OUTSIDE OF THE DEADZONE
+		let x = gamepad.value(Axis::LeftStickX);
+		let y = gamepad.value(Axis::LeftStickY);
+		let stick = match action
+		{
+			Action::Joypad(Button::Right) => x > profile.deadzone,
+			Action::Joypad(Button::Left) => x < -profile.deadzone,
+			Action::Joypad(Button::Up) => y > profile.deadzone,
+			Action::Joypad(Button::Down) => y < -profile.deadzone,
+			_ => false,
+		};
+		pressed || stick
+	}
+}
@@ -0,0 +1,79 @@
+use crate::savestate::StateIo;
+
+// CGB VRAM DMA registers
+pub const HDMA1 : u16 = 0xFF51;	//Source high
+pub const HDMA2 : u16 = 0xFF52;	//Source low (lower 4 bits ignored)
+pub const HDMA3 : u16 = 0xFF53;	//Destination high (only bits 0-4, always in VRAM)
+pub const HDMA4 : u16 = 0xFF54;	//Destination low (lower 4 bits ignored)
+pub const HDMA5 : u16 = 0xFF55;	//Length / mode / start
+
+pub struct Hdma
+{
+	pub source : u16,		//Next source address
+	pub destination : u16,	//Next destination address (offset in VRAM)
+	pub blocks : u8,		//Remaining 16-byte blocks
+	pub active : bool,		//HBlank DMA in progress
+}
+
+impl Hdma
+{
+	pub fn init_hdma() -> Hdma
+	{
+		Hdma
+		{
+			source : 0,
+			destination : 0,
+			blocks : 0,
+			active : false,
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u16(&mut self.source);
+		state.u16(&mut self.destination);
+		state.u8(&mut self.blocks);
+		state.bool(&mut self.active);
+	}
+
+	pub fn read_byte(&self, address : u16) -> u8
+	{
+		match address
+		{
+			// BIT 7 IS SET WHEN NO HBLANK DMA IS ACTIVE, 0xFF ONCE A TRANSFER IS COMPLETE
+			HDMA5 => (if self.active { 0x00 } else { 0x80 }) | (self.blocks.wrapping_sub(1) & 0x7F),
+			_ => 0xFF,
+		}
+	}
+
+	// Returns the number of blocks to copy right away (general purpose DMA)
+	pub fn write_byte(&mut self, address : u16, value : u8) -> u8
+	{
+		match address
+		{
+			HDMA1 => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+			HDMA2 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+			HDMA3 => self.destination = (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+			HDMA4 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+			HDMA5 =>
+			{
+				// WRITING BIT 7 = 0 DURING AN HBLANK DMA CANCELS IT
+				if self.active && value & 0x80 == 0
+				{
+					self.active = false;
+					return 0;
+				}
+
+				self.blocks = (value & 0x7F) + 1;
+				if value & 0x80 != 0
+				{
+					self.active = true;	// HBLANK DMA: 16 BYTES AT EACH HBLANK
+					return 0;
+				}
+				return self.blocks;	// GENERAL PURPOSE DMA: EVERYTHING AT ONCE
+			},
+			_ => (),
+		}
+		0
+	}
+}
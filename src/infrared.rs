@@ -0,0 +1,287 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use crate::savestate::StateIo;
+
+// CGB infrared port (RP). Bit 0 switches the LED on, bit 1 reads 0 while light is received
+// (only when reading is enabled with bits 6-7 = 3).
+pub const RP : u16 = 0xFF56;
+
+const POLL_CYCLES : u64 = 64;			// Cycles between two reads of the socket
+const IDLE_CYCLES : u64 = 70224 * 4;	// Silence after which the timing of the partner is synchronized again
+
+// Light of the other device
+pub trait InfraredLink
+{
+	// The LED was switched at the given cycle
+	fn send(&mut self, led : bool, cycle : u64);
+
+	// Whether the partner's LED is on at the given cycle
+	fn receive(&mut self, cycle : u64) -> bool;
+}
+
+pub struct Infrared
+{
+	control : u8,						//LED (bit 0) and read enable (bits 6-7)
+	cycles : u64,						//CPU cycles since power on, timestamps of the light signal
+	light : bool,						//Light received from the partner
+	pub link : Option<Box<dyn InfraredLink>>,
+}
+
+impl Infrared
+{
+	pub fn init_infrared() -> Infrared
+	{
+		Infrared
+		{
+			control : 0,
+			cycles : 0,
+			light : false,
+			link : None,
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u8(&mut self.control);
+		state.u64(&mut self.cycles);
+		state.bool(&mut self.light);
+	}
+
+	pub fn read_byte(&self) -> u8
+	{
+		let receiving = self.control & 0xC0 == 0xC0 && self.light;
+		0x3C | self.control | if receiving { 0x00 } else { 0x02 }
+	}
+
+	pub fn write_byte(&mut self, value : u8)
+	{
+		let led = value & 0x01 != 0;
+		if led != (self.control & 0x01 != 0)
+		{
+			if let Some(link) = self.link.as_mut()
+			{
+				link.send(led, self.cycles);
+			}
+		}
+		self.control = value & 0xC1;
+	}
+
+	// Advance by a number of CPU cycles
+	pub fn step(&mut self, cycles : u32)
+	{
+		self.cycles += cycles as u64;
+		if let Some(link) = self.link.as_mut()
+		{
+			self.light = link.receive(self.cycles);
+		}
+	}
+}
+
+// Two emulators of the same process facing each other, run in lockstep
+struct Beam
+{
+	leds : Rc<RefCell<[bool; 2]>>,
+	side : usize,
+}
+
+impl InfraredLink for Beam
+{
+	fn send(&mut self, led : bool, _cycle : u64)
+	{
+		self.leds.borrow_mut()[self.side] = led;
+	}
+
+	fn receive(&mut self, _cycle : u64) -> bool
+	{
+		self.leds.borrow()[1 - self.side]
+	}
+}
+
+pub fn connect_infrared(first : &mut Infrared, second : &mut Infrared)
+{
+	let leds = Rc::new(RefCell::new([false; 2]));
+	first.link = Some(Box::new(Beam { leds : leds.clone(), side : 0 }));
+	second.link = Some(Box::new(Beam { leds, side : 1 }));
+}
+
+// Infrared port of another process. Each LED switch is sent with its cycle, the receiver replays
+// the switches with the same spacing: the first one after a silence sets the offset between both clocks.
+pub struct TcpInfrared
+{
+	stream : Option<TcpStream>,
+	received : Vec<u8>,					//Bytes of an incomplete message
+	unsent : Vec<u8>,					//Messages the socket could not take yet, sent first
+	switches : VecDeque<(u64, bool)>,	//Partner LED switches in local cycles
+	offset : Option<i64>,				//Local cycle - partner cycle
+	last_switch : u64,					//Local cycle of the last switch
+	poll_cycle : u64,
+	led : bool,							//Partner LED
+}
+
+impl TcpInfrared
+{
+	// Wait for the partner to connect
+	pub fn listen(port : u16) -> io::Result<TcpInfrared>
+	{
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		let (stream, _) = listener.accept()?;
+		TcpInfrared::init_tcp_infrared(stream)
+	}
+
+	pub fn connect(address : &str) -> io::Result<TcpInfrared>
+	{
+		TcpInfrared::init_tcp_infrared(TcpStream::connect(address)?)
+	}
+
+	fn init_tcp_infrared(stream : TcpStream) -> io::Result<TcpInfrared>
+	{
+		stream.set_nodelay(true)?;
+		stream.set_nonblocking(true)?;
+		Ok(TcpInfrared
+		{
+			stream : Some(stream),
+			received : Vec::new(),
+			unsent : Vec::new(),
+			switches : VecDeque::new(),
+			offset : None,
+			last_switch : 0,
+			poll_cycle : 0,
+			led : false,
+		})
+	}
+
+	// Read the switches sent by the partner, message: LED (1 byte) + cycle (8 bytes LE)
+	fn poll(&mut self, cycle : u64)
+	{
+		self.flush();
+		let Some(stream) = self.stream.as_mut() else { return };
+		let mut buffer = [0; 256];
+		loop
+		{
+			match stream.read(&mut buffer)
+			{
+				Ok(0) => { self.disconnect(); break; },
+				Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(_) => { self.disconnect(); break; },
+			}
+		}
+
+		let messages = self.received.len() / 9;
+		for message in self.received.drain(..messages * 9).collect::<Vec<u8>>().chunks_exact(9)
+		{
+			let led = message[0] != 0;
+			let remote = u64::from_le_bytes(message[1..9].try_into().unwrap());
+			if cycle.saturating_sub(self.last_switch) > IDLE_CYCLES
+			{
+				self.offset = None;
+			}
+			let offset = *self.offset.get_or_insert(cycle as i64 - remote as i64);
+			let local = (remote as i64 + offset).max(0) as u64;
+			self.switches.push_back((local, led));
+			self.last_switch = cycle;
+		}
+	}
+
+	// Write the unsent messages, what the socket does not take now is kept for later
+	fn flush(&mut self)
+	{
+		let Some(stream) = self.stream.as_mut() else { return };
+		while !self.unsent.is_empty()
+		{
+			match stream.write(&self.unsent)
+			{
+				Ok(0) => { self.disconnect(); break; },
+				Ok(n) => { self.unsent.drain(..n); },
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(_) => { self.disconnect(); break; },
+			}
+		}
+	}
+
+	fn disconnect(&mut self)
+	{
+		if self.stream.take().is_some()
+		{
+			println!("Infrared link disconnected");
+		}
+		self.unsent.clear();
+	}
+}
+
+impl InfraredLink for TcpInfrared
+{
+	fn send(&mut self, led : bool, cycle : u64)
+	{
+		if self.stream.is_some()
+		{
+			self.unsent.push(led as u8);
+			self.unsent.extend_from_slice(&cycle.to_le_bytes());
+			self.flush();
+		}
+	}
+
+	fn receive(&mut self, cycle : u64) -> bool
+	{
+		if cycle >= self.poll_cycle + POLL_CYCLES
+		{
+			self.poll_cycle = cycle;
+			self.poll(cycle);
+		}
+
+		// SWITCHES RECEIVED LATE ARE APPLIED RIGHT AWAY
+		while let Some(&(time, led)) = self.switches.front()
+		{
+			if time > cycle
+			{
+				break;
+			}
+			self.led = led;
+			self.switches.pop_front();
+		}
+		self.led
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn send_keeps_what_the_socket_refuses()
+	{
+		let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+		let mut partner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut infrared = TcpInfrared::init_tcp_infrared(listener.accept().unwrap().0).unwrap();
+
+		// MORE SWITCHES THAN THE SOCKET BUFFERS HOLD WHILE THE PARTNER DOES NOT READ
+		const SWITCHES : u64 = 2_000_000;
+		for cycle in 0..SWITCHES
+		{
+			infrared.send(cycle % 2 == 0, cycle);
+		}
+		assert!(infrared.stream.is_some());
+		assert!(!infrared.unsent.is_empty());
+
+		partner.set_nonblocking(true).unwrap();
+		let mut received = Vec::new();
+		let mut buffer = [0; 4096];
+		while received.len() < SWITCHES as usize * 9
+		{
+			infrared.flush();
+			match partner.read(&mut buffer)
+			{
+				Ok(n) => received.extend_from_slice(&buffer[..n]),
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+				Err(err) => panic!("{}", err),
+			}
+		}
+		let last = &received[received.len() - 9..];
+		assert_eq!(last[0], 0);
+		assert_eq!(u64::from_le_bytes(last[1..9].try_into().unwrap()), SWITCHES - 1);
+	}
+}
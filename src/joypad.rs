@@ -0,0 +1,125 @@
+use crate::savestate::StateIo;
+
+// Joypad (JOYP, 0xFF00): the buttons are read as a 2x4 matrix selected by P14/P15.
+// Every bit is active low: 0 = line selected / button pressed.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button
+{
+	Right,
+	Left,
+	Up,
+	Down,
+	A,
+	B,
+	Select,
+	Start,
+}
+
+pub const BUTTONS : [Button; 8] = [Button::Right, Button::Left, Button::Up, Button::Down, Button::A, Button::B, Button::Select, Button::Start];
+
+impl Button
+{
+	// Name used in the configuration files
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Button::Right => "right",
+			Button::Left => "left",
+			Button::Up => "up",
+			Button::Down => "down",
+			Button::A => "a",
+			Button::B => "b",
+			Button::Select => "select",
+			Button::Start => "start",
+		}
+	}
+
+	pub fn from_name(name : &str) -> Option<Button>
+	{
+		BUTTONS.iter().copied().find(|b| b.name() == name.to_ascii_lowercase())
+	}
+}
+
+pub struct Joypad
+{
+	select : u8,						//P14 (bit 4, directions) and P15 (bit 5, actions)
+	pressed : [u8; 4],					//Each controller (SGB multiplayer): bits 0-3 directions, bits 4-7 actions (1 = pressed)
+	pub player : usize,					//Controller read through P10-P13, selected by the SGB
+}
+
+impl Joypad
+{
+	pub fn init_joypad() -> Joypad
+	{
+		Joypad
+		{
+			select : 0x30,
+			pressed : [0; 4],
+			player : 0,
+		}
+	}
+
+	// THE PRESSED BUTTONS COME FROM THE HOST, ONLY THE SELECTED LINES ARE SAVED
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u8(&mut self.select);
+	}
+
+	// P10-P13 input lines (active low)
+	fn lines(&self) -> u8
+	{
+		let mut lines = 0;
+		if self.select & 0x10 == 0
+		{
+			lines |= self.pressed[self.player] & 0x0F;
+		}
+		if self.select & 0x20 == 0
+		{
+			lines |= self.pressed[self.player] >> 4;
+		}
+		!lines & 0x0F
+	}
+
+	// Buttons held on the first controller, same bits as pressed
+	pub fn pressed(&self) -> u8
+	{
+		self.pressed[0]
+	}
+
+	pub fn read_byte(&self) -> u8
+	{
+		0xC0 | self.select | self.lines()
+	}
+
+	// Returns true if the joypad interrupt must be requested
+	pub fn write_byte(&mut self, value : u8) -> bool
+	{
+		let lines = self.lines();
+		self.select = value & 0x30;
+		Joypad::falling_edge(lines, self.lines())
+	}
+
+	// Returns true if the joypad interrupt must be requested. player is 0 except in SGB multiplayer mode.
+	pub fn set_button(&mut self, player : usize, button : Button, pressed : bool) -> bool
+	{
+		let lines = self.lines();
+		let mask = 1 << button as u8;
+		if pressed
+		{
+			self.pressed[player] |= mask;
+		}
+		else
+		{
+			self.pressed[player] &= !mask;
+		}
+		Joypad::falling_edge(lines, self.lines())
+	}
+
+	// THE INTERRUPT IS REQUESTED WHEN ONE OF P10-P13 GOES FROM HIGH TO LOW
+	fn falling_edge(before : u8, after : u8) -> bool
+	{
+		before & !after & 0x0F != 0
+	}
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use macroquad::prelude::KeyCode;
+use crate::joypad::Button;
+use crate::slots::SLOT_COUNT;
+
+// Keyboard bindings of the joypad buttons and frontend hotkeys.
+// The config file has one "action = key" binding per line, '#' starts a comment:
+//     a = Z
+//     start = Enter
+//     fast_forward = Tab
+//     slot_1 = F1
+// An action listed in the file loses its default keys, it can be bound to several keys.
+
+pub const DEFAULT_KEYMAP_FILE : &str = "keymap.cfg";
+
+// Frontend actions that don't go to the emulated joypad
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hotkey
+{
+	SaveState,							//To the current slot
+	LoadState,
+	Slot(usize),						//Load a slot, save it with Shift held
+	UndoLoad,
+	SlotPicker,
+	FastForward,						//While held
+	Menu,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action
+{
+	Joypad(Button),
+	Hotkey(Hotkey),
+}
+
+impl Action
+{
+	pub fn from_name(name : &str) -> Option<Action>
+	{
+		match name.to_ascii_lowercase().as_str()
+		{
+			"save_state" => Some(Action::Hotkey(Hotkey::SaveState)),
+			"load_state" => Some(Action::Hotkey(Hotkey::LoadState)),
+			"undo_load" => Some(Action::Hotkey(Hotkey::UndoLoad)),
+			"slot_picker" => Some(Action::Hotkey(Hotkey::SlotPicker)),
+			"fast_forward" => Some(Action::Hotkey(Hotkey::FastForward)),
+			"menu" => Some(Action::Hotkey(Hotkey::Menu)),
+			name if name.starts_with("slot_") => match name["slot_".len()..].parse::<usize>()
+			{
+				Ok(slot @ 1..=SLOT_COUNT) => Some(Action::Hotkey(Hotkey::Slot(slot - 1))),
+				_ => None,
+			},
+			name => Button::from_name(name).map(Action::Joypad),
+		}
+	}
+}
+
+// Add a binding read from a config file, the first binding of an action replaces its defaults
+pub fn bind<T>(bindings : &mut Vec<(T, Action)>, overridden : &mut Vec<Action>, input : T, action : Action)
+{
+	if !overridden.contains(&action)
+	{
+		overridden.push(action);
+		bindings.retain(|(_, a)| *a != action);
+	}
+	bindings.push((input, action));
+}
+
+pub struct KeyMap
+{
+	pub bindings : Vec<(KeyCode, Action)>,
+}
+
+// Key names accepted in the config file
+const KEY_NAMES : [(&str, KeyCode); 63] =
+[
+	("up", KeyCode::Up), ("down", KeyCode::Down), ("left", KeyCode::Left), ("right", KeyCode::Right),
+	("enter", KeyCode::Enter), ("backspace", KeyCode::Backspace), ("space", KeyCode::Space), ("tab", KeyCode::Tab),
+	("leftshift", KeyCode::LeftShift), ("rightshift", KeyCode::RightShift),
+	("leftcontrol", KeyCode::LeftControl), ("rightcontrol", KeyCode::RightControl),
+	("leftalt", KeyCode::LeftAlt), ("rightalt", KeyCode::RightAlt),
+	("a", KeyCode::A), ("b", KeyCode::B), ("c", KeyCode::C), ("d", KeyCode::D), ("e", KeyCode::E),
+	("f", KeyCode::F), ("g", KeyCode::G), ("h", KeyCode::H), ("i", KeyCode::I), ("j", KeyCode::J),
+	("k", KeyCode::K), ("l", KeyCode::L), ("m", KeyCode::M), ("n", KeyCode::N), ("o", KeyCode::O),
+	("p", KeyCode::P), ("q", KeyCode::Q), ("r", KeyCode::R), ("s", KeyCode::S), ("t", KeyCode::T),
+	("u", KeyCode::U), ("v", KeyCode::V), ("w", KeyCode::W), ("x", KeyCode::X), ("y", KeyCode::Y),
+	("z", KeyCode::Z),
+	("kp0", KeyCode::Kp0), ("kp1", KeyCode::Kp1), ("kp2", KeyCode::Kp2), ("kp3", KeyCode::Kp3),
+	("kp4", KeyCode::Kp4), ("kp5", KeyCode::Kp5), ("kp6", KeyCode::Kp6), ("kp7", KeyCode::Kp7),
+	("kp8", KeyCode::Kp8), ("kp9", KeyCode::Kp9), ("kpenter", KeyCode::KpEnter),
+	("f1", KeyCode::F1), ("f2", KeyCode::F2), ("f3", KeyCode::F3), ("f4", KeyCode::F4), ("f5", KeyCode::F5),
+	("f6", KeyCode::F6), ("f7", KeyCode::F7), ("f8", KeyCode::F8), ("f9", KeyCode::F9), ("f10", KeyCode::F10),
+	("f11", KeyCode::F11), ("f12", KeyCode::F12),
+];
+
+pub fn key_from_name(name : &str) -> Option<KeyCode>
+{
+	let name = name.to_ascii_lowercase();
+	KEY_NAMES.iter().find(|(key_name, _)| *key_name == name).map(|&(_, key)| key)
+}
+
+impl KeyMap
+{
+	// Arrows, Z = A, X = B, Enter = Start, Backspace = Select, Tab = fast forward, P = menu,
+	// S = save state, L = load state (current slot), F1-F10 = load slot 1-10 (Shift + F1-F10 saves),
+	// F11 = slot picker, F12 = undo the last load
+	pub fn init_keymap() -> KeyMap
+	{
+		KeyMap
+		{
+			bindings : vec![
+				(KeyCode::Right, Action::Joypad(Button::Right)),
+				(KeyCode::Left, Action::Joypad(Button::Left)),
+				(KeyCode::Up, Action::Joypad(Button::Up)),
+				(KeyCode::Down, Action::Joypad(Button::Down)),
+				(KeyCode::Z, Action::Joypad(Button::A)),
+				(KeyCode::X, Action::Joypad(Button::B)),
+				(KeyCode::Backspace, Action::Joypad(Button::Select)),
+				(KeyCode::Enter, Action::Joypad(Button::Start)),
+				(KeyCode::Tab, Action::Hotkey(Hotkey::FastForward)),
+				(KeyCode::P, Action::Hotkey(Hotkey::Menu)),
+				(KeyCode::S, Action::Hotkey(Hotkey::SaveState)),
+				(KeyCode::L, Action::Hotkey(Hotkey::LoadState)),
+				(KeyCode::F1, Action::Hotkey(Hotkey::Slot(0))),
+				(KeyCode::F2, Action::Hotkey(Hotkey::Slot(1))),
+				(KeyCode::F3, Action::Hotkey(Hotkey::Slot(2))),
+				(KeyCode::F4, Action::Hotkey(Hotkey::Slot(3))),
+				(KeyCode::F5, Action::Hotkey(Hotkey::Slot(4))),
+				(KeyCode::F6, Action::Hotkey(Hotkey::Slot(5))),
+				(KeyCode::F7, Action::Hotkey(Hotkey::Slot(6))),
+				(KeyCode::F8, Action::Hotkey(Hotkey::Slot(7))),
+				(KeyCode::F9, Action::Hotkey(Hotkey::Slot(8))),
+				(KeyCode::F10, Action::Hotkey(Hotkey::Slot(9))),
+				(KeyCode::F11, Action::Hotkey(Hotkey::SlotPicker)),
+				(KeyCode::F12, Action::Hotkey(Hotkey::UndoLoad)),
+			],
+		}
+	}
+
+	// Default bindings overridden by the config file, a missing file keeps the defaults
+	pub fn load(path : &str) -> Result<KeyMap, String>
+	{
+		let mut keymap = KeyMap::init_keymap();
+		let text = match fs::read_to_string(path)
+		{
+			Ok(text) => text,
+			Err(_) => return Ok(keymap),
+		};
+
+		let mut overridden = Vec::new();
+		for (number, line) in text.lines().enumerate()
+		{
+			let line = line.split('#').next().unwrap_or("").trim();
+			if line.is_empty()
+			{
+				continue;
+			}
+
+			let (action_name, key_name) = line.split_once('=')
+				.ok_or(format!("{}:{}: expected \"action = key\"", path, number + 1))?;
+			let action = Action::from_name(action_name.trim())
+				.ok_or(format!("{}:{}: unknown action \"{}\"", path, number + 1, action_name.trim()))?;
+			let key = key_from_name(key_name.trim())
+				.ok_or(format!("{}:{}: unknown key \"{}\"", path, number + 1, key_name.trim()))?;
+			bind(&mut keymap.bindings, &mut overridden, key, action);
+		}
+		Ok(keymap)
+	}
+
+	// Whether an action is held (key_down) or was just pressed (key_pressed) on the keyboard
+	pub fn is_active(&self, action : Action, key_state : impl Fn(KeyCode) -> bool) -> bool
+	{
+		self.bindings.iter().any(|&(key, a)| a == action && key_state(key))
+	}
+}
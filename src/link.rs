@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::time::Duration;
+use crate::emulator::{Emulator, CYCLES_PER_FRAME};
+use crate::serial::SerialLink;
+use crate::infrared::connect_infrared;
+
+// Link cable between two serial ports. The game using the internal clock drives each transfer,
+// both sides are synchronized when a byte is exchanged.
+
+// State of one end of the in-process cable
+#[derive(Default)]
+struct Plug
+{
+	data : u8,							//SB shifted out when the partner clocks a transfer
+	ready : bool,						//Transfer started with the external clock
+	inbox : Option<u8>,					//Byte received from the partner, not seen yet
+}
+
+// One end of a cable between two emulators of the same process
+struct CableEnd
+{
+	plugs : Rc<RefCell<[Plug; 2]>>,
+	side : usize,
+}
+
+impl SerialLink for CableEnd
+{
+	fn exchange(&mut self, outgoing : u8) -> u8
+	{
+		let mut plugs = self.plugs.borrow_mut();
+		let partner = &mut plugs[1 - self.side];
+		if !partner.ready
+		{
+			return 0xFF;
+		}
+		partner.ready = false;
+		partner.inbox = Some(outgoing);
+		partner.data
+	}
+
+	fn poll(&mut self, data : u8, ready : bool) -> Option<u8>
+	{
+		let mut plugs = self.plugs.borrow_mut();
+		let plug = &mut plugs[self.side];
+		plug.data = data;
+		plug.ready = ready && plug.inbox.is_none();
+		plug.inbox.take()
+	}
+}
+
+// Two emulators of the same process connected by a link cable (and facing each other for infrared), run in lockstep
+pub struct LinkCable
+{
+	pub emulators : [Emulator; 2],
+	cycles : [u32; 2],					//Cycles run by each emulator in the current frame
+}
+
+impl LinkCable
+{
+	pub fn connect(mut first : Emulator, mut second : Emulator) -> LinkCable
+	{
+		let plugs = Rc::new(RefCell::new([Plug::default(), Plug::default()]));
+		first.mem_bus.serial.link = Some(Box::new(CableEnd { plugs : plugs.clone(), side : 0 }));
+		second.mem_bus.serial.link = Some(Box::new(CableEnd { plugs, side : 1 }));
+		connect_infrared(&mut first.mem_bus.infrared, &mut second.mem_bus.infrared);
+		LinkCable
+		{
+			emulators : [first, second],
+			cycles : [0; 2],
+		}
+	}
+
+	// Run both emulators for one frame, the one behind always runs the next instruction
+	pub fn run_frame(&mut self)
+	{
+		while self.cycles.iter().any(|&c| c < CYCLES_PER_FRAME)
+		{
+			let i = if self.cycles[0] <= self.cycles[1] { 0 } else { 1 };
+			self.cycles[i] += self.emulators[i].emulation_cycle();
+		}
+		for cycles in self.cycles.iter_mut()
+		{
+			*cycles -= CYCLES_PER_FRAME;
+		}
+	}
+}
+
+// TCP messages: a tag byte, the sequence number of the transfer and the transferred byte.
+// A reply carries the number of the transfer it answers, a late reply to a transfer that
+// timed out is dropped instead of being taken as the reply to the next one.
+const MSG_TRANSFER : u8 = 0x01;		// Byte sent by the side using the internal clock
+const MSG_REPLY : u8 = 0x02;		// Byte sent back by the other side
+const MESSAGE_SIZE : usize = 3;
+const REPLY_TIMEOUT : Duration = Duration::from_secs(2);
+
+// Link cable to another process over TCP
+pub struct TcpLink
+{
+	stream : Option<TcpStream>,			//None once the partner is gone
+	received : Vec<u8>,					//Bytes of an incomplete message
+	sequence : u8,						//Number of the last transfer sent
+}
+
+impl TcpLink
+{
+	// Wait for the partner to connect
+	pub fn listen(port : u16) -> io::Result<TcpLink>
+	{
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		let (stream, _) = listener.accept()?;
+		TcpLink::init_tcp_link(stream)
+	}
+
+	pub fn connect(address : &str) -> io::Result<TcpLink>
+	{
+		TcpLink::init_tcp_link(TcpStream::connect(address)?)
+	}
+
+	fn init_tcp_link(stream : TcpStream) -> io::Result<TcpLink>
+	{
+		stream.set_nodelay(true)?;
+		stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+		Ok(TcpLink
+		{
+			stream : Some(stream),
+			received : Vec::new(),
+			sequence : 0,
+		})
+	}
+
+	fn send(&mut self, tag : u8, sequence : u8, value : u8)
+	{
+		if let Some(stream) = self.stream.as_mut()
+		{
+			if stream.write_all(&[tag, sequence, value]).is_err()
+			{
+				self.disconnect();
+			}
+		}
+	}
+
+	// Next message from the partner, waits for it when blocking
+	fn receive(&mut self, blocking : bool) -> Option<(u8, u8, u8)>
+	{
+		let stream = self.stream.as_mut()?;
+		if stream.set_nonblocking(!blocking).is_err()
+		{
+			self.disconnect();
+			return None;
+		}
+
+		let mut buffer = [0; MESSAGE_SIZE];
+		while self.received.len() < MESSAGE_SIZE
+		{
+			match stream.read(&mut buffer[..MESSAGE_SIZE - self.received.len()])
+			{
+				Ok(0) => { self.disconnect(); return None; },
+				Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+				// NOTHING YET, OR NO REPLY BEFORE THE TIMEOUT: THE LINK STAYS UP
+				Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return None,
+				Err(_) => { self.disconnect(); return None; },
+			}
+		}
+		let message = (self.received[0], self.received[1], self.received[2]);
+		self.received.clear();
+		Some(message)
+	}
+
+	fn disconnect(&mut self)
+	{
+		if self.stream.take().is_some()
+		{
+			println!("Link cable disconnected");
+		}
+	}
+}
+
+impl SerialLink for TcpLink
+{
+	fn exchange(&mut self, outgoing : u8) -> u8
+	{
+		self.sequence = self.sequence.wrapping_add(1);
+		self.send(MSG_TRANSFER, self.sequence, outgoing);
+		loop
+		{
+			match self.receive(true)
+			{
+				Some((MSG_REPLY, sequence, incoming)) if sequence == self.sequence => return incoming,
+				// BOTH SIDES USE THE INTERNAL CLOCK: NOBODY LISTENS
+				Some((MSG_TRANSFER, sequence, _)) => self.send(MSG_REPLY, sequence, 0xFF),
+				// LATE REPLY TO AN EARLIER TRANSFER
+				Some(_) => (),
+				None => return 0xFF,
+			}
+		}
+	}
+
+	fn poll(&mut self, data : u8, ready : bool) -> Option<u8>
+	{
+		match self.receive(false)?
+		{
+			(MSG_TRANSFER, sequence, incoming) =>
+			{
+				self.send(MSG_REPLY, sequence, if ready { data } else { 0xFF });
+				Some(incoming)
+			},
+			_ => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn late_reply_is_dropped()
+	{
+		let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+		let mut partner = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut link = TcpLink::init_tcp_link(listener.accept().unwrap().0).unwrap();
+
+		// THE REPLY TO TRANSFER 7 ARRIVES AFTER ITS TIMEOUT, BEFORE THE REPLY TO TRANSFER 8
+		link.sequence = 7;
+		partner.write_all(&[MSG_REPLY, 7, 0x11, MSG_REPLY, 8, 0x22]).unwrap();
+		assert_eq!(link.exchange(0x33), 0x22);
+
+		let mut transfer = [0; MESSAGE_SIZE];
+		partner.read_exact(&mut transfer).unwrap();
+		assert_eq!(transfer, [MSG_TRANSFER, 8, 0x33]);
+	}
+}
@@ -4,10 +4,47 @@ mod bus;
 mod register;
 mod cpu;
 mod ppu;
+mod timer;
+mod hdma;
+mod compat;
+mod sgb;
+mod apu;
+mod audio;
+mod wav;
+mod joypad;
+mod keymap;
+#[cfg(feature = "gamepad")]
+mod gamepad;
+mod serial;
+mod link;
+mod printer;
+mod infrared;
+mod licensee;
+mod archive;
+mod patch;
+mod romdb;
+mod cheats;
+mod search;
+mod savestate;
+mod slots;
 
 use std::time::{SystemTime, Duration};
+use std::io::Write;
 use macroquad::prelude::*;
-use emulator::Emulator;
+use emulator::{Emulator, Model};
+use cartridge::{Cartridge, RomError};
+use audio::AudioOutput;
+use wav::WavRecorder;
+use joypad::BUTTONS;
+use keymap::{KeyMap, Action, Hotkey};
+#[cfg(feature = "gamepad")]
+use gamepad::{Gamepads, GamepadProfile};
+use link::{LinkCable, TcpLink};
+use printer::Printer;
+use infrared::TcpInfrared;
+use slots::{SaveSlots, SlotInfo, SLOT_COUNT};
+use cheats::Cheats;
+use search::{RamSearch, Filter, Width};
 
 const SIZE : (i32, i32) = (160, 144);
 
@@ -22,65 +59,772 @@ fn window_conf() -> Conf
     }
 }
 
-#[macroquad::main(window_conf)]
-async fn main() 
+fn main() 
 {
-    // TEMPORARY GAMEBOY BUFFER
-    let buffer = vec![255; 160 * 144 * 4];
+    // EMULATOR
+    // ARGUMENTS: [ROM] [--rom-entry <name in the archive>] [--model dmg|cgb|sgb] [--palette <combo or name>] [--no-audio] [--audio-sync]
+    //            [--mute <channels>] [--solo <channel>] [--record <file.wav>] [--split-channels] [--frames <count>]
+    //            [--keymap <file>] [--gamepads <file>] [--serial stdout|<file>]
+    //            [--link-rom <rom>] [--link-host <port>] [--link-connect <address>] [--printer <output prefix>]
+    //            [--ir-host <port>] [--ir-connect <address>] [--cheats <file>] [--romdb <file>]
+    //        or: info <rom>
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("info")
+    {
+        if !load_romdb(romdb::DEFAULT_ROMDB_FILE)
+        {
+            std::process::exit(1);
+        }
+        match args.get(1)
+        {
+            Some(path) => std::process::exit(if print_rom_info(path) { 0 } else { 1 }),
+            None => { println!("Usage: rustic_boy info <rom>"); std::process::exit(1); }
+        }
+    }
+    let mut rom = "roms/tetris.gb".to_string();
+    let mut rom_entry = None;
+    let mut model = None;
+    let mut palette = None;
+    let mut audio_enabled = true;
+    let mut audio_sync = false;
+    let mut muted = [false; 4];
+    let mut solo = None;
+    let mut record = None;
+    let mut split_channels = false;
+    let mut frames = None;
+    let mut keymap_file = keymap::DEFAULT_KEYMAP_FILE.to_string();
+    let mut romdb_file = romdb::DEFAULT_ROMDB_FILE.to_string();
+    #[cfg(feature = "gamepad")]
+    let mut gamepad_file = gamepad::DEFAULT_GAMEPAD_FILE.to_string();
+    let mut serial = None;
+    let mut link_rom = None;
+    let mut link_host = None;
+    let mut link_connect = None;
+    let mut printer = None;
+    let mut cheat_file = None;
+    let mut ir_host = None;
+    let mut ir_connect = None;
+    let mut i = 0;
+    while i < args.len()
+    {
+        match args[i].as_str()
+        {
+            "--rom-entry" =>
+            {
+                i += 1;
+                rom_entry = args.get(i).cloned();
+            },
+            "--model" =>
+            {
+                i += 1;
+                model = match args.get(i).map(|m| m.as_str())
+                {
+                    Some("dmg") => Some(Model::Dmg),
+                    Some("cgb") => Some(Model::Cgb),
+                    Some("sgb") => Some(Model::Sgb),
+                    _ => { println!("Unknown model, expected dmg, cgb or sgb"); return; }
+                };
+            },
+            "--palette" =>
+            {
+                i += 1;
+                palette = args.get(i).and_then(|p| compat::find_palette(p));
+                if palette.is_none()
+                {
+                    println!("Unknown palette, expected one of:");
+                    for p in compat::COMPAT_PALETTES.iter()
+                    {
+                        println!("  {} ({})", p.combo, p.name);
+                    }
+                    return;
+                }
+            },
+            "--no-audio" => audio_enabled = false,
+            "--audio-sync" => audio_sync = true,    // PACE THE EMULATION ON THE AUDIO BUFFER
+            "--mute" =>
+            {
+                // COMMA SEPARATED CHANNEL NUMBERS (1-4)
+                i += 1;
+                for channel in args.get(i).map(|c| c.as_str()).unwrap_or("").split(',')
+                {
+                    match parse_channel(channel)
+                    {
+                        Some(channel) => muted[channel] = true,
+                        None => { println!("Unknown channel, expected 1, 2, 3 or 4"); return; }
+                    }
+                }
+            },
+            "--solo" =>
+            {
+                i += 1;
+                solo = args.get(i).and_then(|c| parse_channel(c));
+                if solo.is_none()
+                {
+                    println!("Unknown channel, expected 1, 2, 3 or 4");
+                    return;
+                }
+            },
+            "--record" =>
+            {
+                i += 1;
+                record = args.get(i).cloned();
+            },
+            "--split-channels" => split_channels = true,   // ONE WAV FILE PER CHANNEL
+            "--frames" =>
+            {
+                // RUN WITHOUT A WINDOW FOR A NUMBER OF FRAMES
+                i += 1;
+                frames = args.get(i).and_then(|f| f.parse::<u32>().ok());
+                if frames.is_none()
+                {
+                    println!("Expected a number of frames");
+                    return;
+                }
+            },
+            "--keymap" =>
+            {
+                i += 1;
+                keymap_file = args.get(i).cloned().unwrap_or_default();
+            },
+            "--romdb" =>
+            {
+                // ROM DATABASE ADDED TO THE BUILT-IN ONE
+                i += 1;
+                romdb_file = args.get(i).cloned().unwrap_or_default();
+            },
+            #[cfg(feature = "gamepad")]
+            "--gamepads" =>
+            {
+                // BINDING PROFILES OF THE CONTROLLERS
+                i += 1;
+                gamepad_file = args.get(i).cloned().unwrap_or_default();
+            },
+            "--serial" =>
+            {
+                // BYTES SENT ON THE SERIAL PORT (TEST ROM OUTPUT)
+                i += 1;
+                serial = args.get(i).cloned();
+            },
+            "--link-rom" =>
+            {
+                // SECOND EMULATOR CONNECTED BY A LINK CABLE
+                i += 1;
+                link_rom = args.get(i).cloned();
+            },
+            "--link-host" =>
+            {
+                // WAIT FOR ANOTHER PROCESS TO CONNECT ITS LINK CABLE
+                i += 1;
+                link_host = args.get(i).and_then(|p| p.parse::<u16>().ok());
+                if link_host.is_none()
+                {
+                    println!("Expected a port number");
+                    return;
+                }
+            },
+            "--link-connect" =>
+            {
+                i += 1;
+                link_connect = args.get(i).cloned();
+            },
+            "--cheats" =>
+            {
+                // CHEAT LIST, <rom>.cht BY DEFAULT
+                i += 1;
+                cheat_file = args.get(i).cloned();
+            },
+            "--printer" =>
+            {
+                // GAME BOY PRINTER ON THE SERIAL PORT, PRINTS ARE SAVED AS <prefix>_<n>.png
+                i += 1;
+                printer = args.get(i).cloned();
+            },
+            "--ir-host" =>
+            {
+                // WAIT FOR ANOTHER PROCESS TO FACE THE INFRARED PORT
+                i += 1;
+                ir_host = args.get(i).and_then(|p| p.parse::<u16>().ok());
+                if ir_host.is_none()
+                {
+                    println!("Expected a port number");
+                    return;
+                }
+            },
+            "--ir-connect" =>
+            {
+                i += 1;
+                ir_connect = args.get(i).cloned();
+            },
+            path => rom = path.to_string(),
+        }
+        i += 1;
+    }
 
-    // GAMEBOY RENDER IMAGE
-    let gb_image = Image{
-        width : 160,
-        height : 144,
-        bytes : buffer,
+    if !load_romdb(&romdb_file)
+    {
+        return;
+    }
+    let mut gb_emulator = match create_emulator(&rom, rom_entry.as_deref(), model, palette)
+    {
+        Ok(emulator) => emulator,
+        Err(err) => { println!("Cannot load {}: {}", rom, err); return; }
     };
+    gb_emulator.mem_bus.apu.muted = muted;
+    gb_emulator.mem_bus.apu.solo = solo;
 
-    // GAMEBOY TEXTURE
-    let gb_texture = Texture2D::from_image(&gb_image);
+    // CHEATS
+    let cheat_file = cheat_file.unwrap_or_else(|| std::path::Path::new(&rom).with_extension(cheats::CHEAT_EXTENSION).display().to_string());
+    gb_emulator.mem_bus.cheats = match Cheats::load(&cheat_file)
+    {
+        Ok(cheats) => cheats,
+        Err(err) => { println!("{}", err); return; }
+    };
 
-    // EMULATOR
+    // WAV RECORDING
+    let recorder = match record
+    {
+        Some(path) => match WavRecorder::start(&path, split_channels, &mut gb_emulator.mem_bus.apu)
+        {
+            Ok(recorder) => Some(recorder),
+            Err(err) => { println!("Cannot record to {}: {}", path, err); return; }
+        },
+        None => None,
+    };
+
+    // SERIAL OUTPUT
+    let serial : Option<Box<dyn Write>> = match serial.as_deref()
+    {
+        Some("stdout") => Some(Box::new(std::io::stdout())),
+        Some(path) => match std::fs::File::create(path)
+        {
+            Ok(file) => Some(Box::new(file)),
+            Err(err) => { println!("Cannot create {}: {}", path, err); return; }
+        },
+        None => None,
+    };
+    let outputs = Outputs { recorder, serial };
+
+    // GAME BOY PRINTER
+    if let Some(output) = printer
+    {
+        gb_emulator.mem_bus.serial.link = Some(Box::new(Printer::init_printer(&output)));
+    }
+
+    // LINK CABLE TO ANOTHER PROCESS
+    let tcp_link = match (link_host, link_connect)
+    {
+        (Some(port), _) =>
+        {
+            println!("Waiting for the link cable partner on port {}", port);
+            Some(TcpLink::listen(port))
+        },
+        (None, Some(address)) => Some(TcpLink::connect(&address)),
+        (None, None) => None,
+    };
+    match tcp_link
+    {
+        Some(Ok(link)) => gb_emulator.mem_bus.serial.link = Some(Box::new(link)),
+        Some(Err(err)) => { println!("Cannot connect the link cable: {}", err); return; }
+        None => (),
+    }
+
+    // INFRARED LINK TO ANOTHER PROCESS
+    let tcp_infrared = match (ir_host, ir_connect)
+    {
+        (Some(port), _) =>
+        {
+            println!("Waiting for the infrared partner on port {}", port);
+            Some(TcpInfrared::listen(port))
+        },
+        (None, Some(address)) => Some(TcpInfrared::connect(&address)),
+        (None, None) => None,
+    };
+    match tcp_infrared
+    {
+        Some(Ok(link)) => gb_emulator.mem_bus.infrared.link = Some(Box::new(link)),
+        Some(Err(err)) => { println!("Cannot connect the infrared link: {}", err); return; }
+        None => (),
+    }
+
+    // LINK CABLE TO A SECOND EMULATOR
+    let machine = match link_rom
+    {
+        Some(path) => match create_emulator(&path, None, model, palette)
+        {
+            Ok(second) => Machine::Linked(Box::new(LinkCable::connect(gb_emulator, second))),
+            Err(err) => { println!("Cannot load {}: {}", path, err); return; }
+        },
+        None => Machine::Single(Box::new(gb_emulator)),
+    };
+
+    // KEYBOARD BINDINGS
+    let keymap = match KeyMap::load(&keymap_file)
+    {
+        Ok(keymap) => keymap,
+        Err(err) => { println!("{}", err); return; }
+    };
+    #[cfg(feature = "gamepad")]
+    let gamepad_profiles = match Gamepads::load_profiles(&gamepad_file)
+    {
+        Ok(profiles) => profiles,
+        Err(err) => { println!("{}", err); return; }
+    };
+
+    match frames
+    {
+        Some(frames) => run_headless(machine, outputs, frames),
+        #[cfg(feature = "gamepad")]
+        None => macroquad::Window::from_config(window_conf(), run_window(machine, outputs, keymap, gamepad_profiles, audio_enabled, audio_sync, cheat_file)),
+        #[cfg(not(feature = "gamepad"))]
+        None => macroquad::Window::from_config(window_conf(), run_window(machine, outputs, keymap, audio_enabled, audio_sync, cheat_file)),
+    }
+}
+
+// Entries of a ROM database file, returns false on a bad file
+fn load_romdb(path : &str) -> bool
+{
+    match romdb::load_database(path)
+    {
+        Ok(0) => true,
+        Ok(count) => { println!("ROM database: {} entries from {}", count, path); true },
+        Err(err) => { println!("{}", err); false },
+    }
+}
+
+// Header and integrity report of a ROM, returns true for a good dump
+fn print_rom_info(path : &str) -> bool
+{
+    let mut cart = Cartridge::init_cartridge();
+    let opened = archive::read_rom(path, None).and_then(|data| cart.open_cartridge(path, data));
+    if let Err(err) = opened
+    {
+        println!("Cannot read {}: {}", path, err);
+        return false;
+    }
+    let report = cart.validation_report();
+    println!("{}", cart.info());
+    println!("{}", report);
+    if let Err(err) = cart.header.validate(cart.data.len())
+    {
+        println!("Cannot be run: {}", err);
+    }
+    report.is_valid()
+}
+
+fn create_emulator(rom : &str, entry : Option<&str>, model : Option<Model>, palette : Option<usize>) -> Result<Emulator, RomError>
+{
     let mut gb_emulator : Emulator = Emulator::init_emulator();
-    if !gb_emulator.load_boot_rom("roms/dmg_boot.bin")  // "roms/dmg_boot.bin"
+    gb_emulator.load_rom(rom, entry)?; // LOAD ROM (SELECTS THE MODEL)
+    if let Some(index) = palette
+    {
+        gb_emulator.set_compat_palette(index);
+    }
+    if let Some(model) = model
+    {
+        gb_emulator.set_model(model);
+    }
+    let boot_rom = match gb_emulator.model
+    {
+        Model::Dmg => "roms/dmg_boot.bin",
+        Model::Cgb => "roms/cgb_boot.bin",
+        Model::Sgb => "roms/sgb_boot.bin",
+    };
+    if !gb_emulator.load_boot_rom(boot_rom)
     {
         gb_emulator.init_emulator_without_bootrom(); // SKIP ROM BOOT
     }
-    gb_emulator.load_rom("roms/tetris.gb"); // LOAD ROM
+    Ok(gb_emulator)
+}
+
+// One emulator, or two connected by a link cable
+enum Machine
+{
+    Single(Box<Emulator>),
+    Linked(Box<LinkCable>),
+}
+
+impl Machine
+{
+    fn emulators(&mut self) -> &mut [Emulator]
+    {
+        match self
+        {
+            Machine::Single(gb_emulator) => std::slice::from_mut(gb_emulator.as_mut()),
+            Machine::Linked(cable) => &mut cable.emulators,
+        }
+    }
+
+    fn run_frame(&mut self)
+    {
+        match self
+        {
+            Machine::Single(gb_emulator) => gb_emulator.run_frame(),
+            Machine::Linked(cable) => cable.run_frame(),
+        }
+    }
+}
+
+// Channel number from 1 to 4 on the command line
+fn parse_channel(channel : &str) -> Option<usize>
+{
+    match channel.trim().parse::<usize>()
+    {
+        Ok(n @ 1..=4) => Some(n - 1),
+        _ => None,
+    }
+}
+
+// Streams filled by the emulator besides the screen
+struct Outputs
+{
+    recorder : Option<WavRecorder>,
+    serial : Option<Box<dyn Write>>,
+}
+
+impl Outputs
+{
+    // Send the samples of the last frames to the audio device and the WAV recorder, the serial bytes to the log.
+    // Only the first emulator of a linked pair is heard and logged.
+    fn frame_done(&mut self, machine : &mut Machine, audio : Option<&mut AudioOutput>)
+    {
+        let (gb_emulator, others) = machine.emulators().split_first_mut().unwrap();
+        for other in others.iter_mut()
+        {
+            other.mem_bus.apu.samples.clear();
+            other.mem_bus.serial.output.clear();
+        }
+
+        let samples = std::mem::take(&mut gb_emulator.mem_bus.apu.samples);
+        if let Some(output) = audio
+        {
+            output.push_samples(&samples);
+        }
+        if let Some(wav) = self.recorder.as_mut()
+        {
+            if let Err(err) = wav.record(&samples, &mut gb_emulator.mem_bus.apu)
+            {
+                println!("WAV recording stopped: {}", err);
+                self.recorder = None;
+            }
+        }
+
+        let bytes = std::mem::take(&mut gb_emulator.mem_bus.serial.output);
+        if let Some(serial) = self.serial.as_mut()
+        {
+            if !bytes.is_empty() && serial.write_all(&bytes).and_then(|_| serial.flush()).is_err()
+            {
+                println!("Serial output stopped");
+                self.serial = None;
+            }
+        }
+    }
 
-    // CLOCK    
-    const CLOCK_SPEED: u32 = 4_194_304;   // Hz
-    const CYCLES_PER_FRAME: u32 = 70224;  // (CLOCK SPEED / REFRESH RATE)
-    const TARGET_WAIT_TIME: u64 = 1666666667;
-    let mut cycles : u32 = 0;
+    fn finish(self)
+    {
+        if let Some(Err(err)) = self.recorder.map(|wav| wav.finish())
+        {
+            println!("Cannot finish the WAV recording: {}", err);
+        }
+    }
+}
+
+fn run_headless(mut machine : Machine, mut outputs : Outputs, frames : u32)
+{
+    for _ in 0..frames
+    {
+        machine.run_frame();
+        outputs.frame_done(&mut machine, None);
+    }
+    outputs.finish();
+}
+
+async fn run_window(mut machine : Machine, mut outputs : Outputs, keymap : KeyMap, #[cfg(feature = "gamepad")] gamepad_profiles : Vec<GamepadProfile>, audio_enabled : bool, audio_sync : bool, cheat_file : String)
+{
+    // GAMEBOY BUFFER (256x224 WITH THE SGB BORDER), LINKED EMULATORS ARE SIDE BY SIDE
+    let (mut width, mut height) = machine.emulators()[0].screen_size();
+    let count = machine.emulators().len();
+    request_new_screen_size((width * count) as f32, height as f32);
+
+    // GAMEBOY RENDER IMAGES AND TEXTURES
+    let mut screens : Vec<(Image, Texture2D)> = (0..count).map(|_| new_screen(width, height)).collect();
+
+    // AUDIO
+    let mut audio = if audio_enabled { AudioOutput::open() } else { None };
+    if audio_enabled && audio.is_none()
+    {
+        println!("No audio output device, running without sound");
+    }
+
+    // GAMEPADS
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = Gamepads::init_gamepads(gamepad_profiles);
+
+    // CLOCK
+    const FRAME_TIME: u64 = 16_742_706;     // ns (CYCLES PER FRAME / CLOCK SPEED)
+    const AUDIO_TARGET: usize = 2048;       // AUDIO FRAMES KEPT IN THE BUFFER WITH --audio-sync
+    const MAX_FRAMES_PER_UPDATE: u32 = 4;
+    const FAST_FORWARD_FRAMES: u32 = 4;     // FRAMES PER UPDATE WHILE FAST FORWARDING
+    const CHANNEL_KEYS: [KeyCode; 4] = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4];
     let mut start_time = SystemTime::now();
+    let mut pressed = [[false; 8]; 4];      // BUTTONS HELD BY EACH PLAYER (SGB MULTIPLAYER)
+    let mut paused = false;
+    let mut selected_cheat = 0;             // CHEAT HIGHLIGHTED IN THE MENU
+
+    // SAVE STATE SLOTS OF EACH EMULATOR, THE PICKER PAUSES THE EMULATION WHILE OPEN
+    let mut slots : Vec<SaveSlots> = machine.emulators().iter().map(|e| SaveSlots::init_slots(&e.cart.filename)).collect();
+    let mut picker : Option<SlotPicker> = None;
+
+    // RAM SEARCH COMMANDS TYPED IN THE TERMINAL
+    let console = spawn_console();
+    let mut search = None;
+    let mut focus = 0;                      // EMULATOR RECEIVING THE INPUT, CLICK A SCREEN TO FOCUS IT
 
     // CLEAR SCREEN
     clear_background(BLACK);
     loop 
     {
-        // EMULATION CYCLE
-        cycles += gb_emulator.emulation_cycle();
+        // FOCUS
+        if is_mouse_button_pressed(MouseButton::Left)
+        {
+            let clicked = ((mouse_position().0 / width as f32) as usize).min(count - 1);
+            if clicked != focus
+            {
+                // RELEASE THE BUTTONS HELD ON THE PREVIOUS SCREEN
+                for (player, held) in pressed.iter_mut().enumerate()
+                {
+                    for (i, &button) in BUTTONS.iter().enumerate()
+                    {
+                        if held[i]
+                        {
+                            machine.emulators()[focus].set_button(player, button, false);
+                            held[i] = false;
+                        }
+                    }
+                }
+                focus = clicked;
+            }
+        }
+
+        // JOYPAD (KEYBOARD AND GAMEPADS)
+        #[cfg(feature = "gamepad")]
+        let mut hotkeys = gamepads.as_mut().map(|g| g.poll()).unwrap_or_default();
+        #[cfg(not(feature = "gamepad"))]
+        let mut hotkeys = Vec::new();
+
+        // HELD ON ANY GAMEPAD (None) OR ON THE NTH ONE
+        #[cfg(feature = "gamepad")]
+        let gamepad_down = |controller : Option<usize>, action| gamepads.as_ref().is_some_and(|g| match controller
+        {
+            Some(n) => g.is_down_on(n, action),
+            None => g.is_down(action),
+        });
+        #[cfg(not(feature = "gamepad"))]
+        let gamepad_down = |_ : Option<usize>, _ : Action| false;
+        let action_down = |action| keymap.is_active(action, is_key_down) || gamepad_down(None, action);
+
+        // AFTER AN SGB MLT_REQ, THE NTH CONTROLLER IS PLAYER N AND THE KEYBOARD STAYS PLAYER 1
+        let players = machine.emulators()[focus].players();
+        for (player, held) in pressed.iter_mut().enumerate()
+        {
+            for (i, &button) in BUTTONS.iter().enumerate()
+            {
+                let action = Action::Joypad(button);
+                let down = match player
+                {
+                    _ if players == 1 => player == 0 && action_down(action),
+                    0 => keymap.is_active(action, is_key_down) || gamepad_down(Some(0), action),
+                    _ => player < players && gamepad_down(Some(player), action),
+                };
+                if down != held[i]
+                {
+                    held[i] = down;
+                    machine.emulators()[focus].set_button(player, button, down);
+                }
+            }
+        }
+        let fast_forward = action_down(Action::Hotkey(Hotkey::FastForward));
 
-        if cycles >= CYCLES_PER_FRAME
+        // HOTKEYS
+        let shift = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        let keyboard_hotkeys = [Hotkey::SaveState, Hotkey::LoadState, Hotkey::UndoLoad, Hotkey::SlotPicker, Hotkey::Menu]
+            .into_iter().chain((0..SLOT_COUNT).map(Hotkey::Slot));
+        for hotkey in keyboard_hotkeys
+        {
+            if keymap.is_active(Action::Hotkey(hotkey), is_key_pressed)
+            {
+                hotkeys.push(hotkey);
+            }
+        }
+        for hotkey in hotkeys
         {
-            cycles = 0;
+            let emulator = &mut machine.emulators()[focus];
+            let current = slots[focus].current;
+            match hotkey
+            {
+                Hotkey::SaveState => save_slot(&mut slots[focus], current, emulator),
+                Hotkey::LoadState => load_slot(&mut slots[focus], current, emulator),
+                Hotkey::Slot(slot) if shift => save_slot(&mut slots[focus], slot, emulator),
+                Hotkey::Slot(slot) => load_slot(&mut slots[focus], slot, emulator),
+                Hotkey::UndoLoad => match slots[focus].undo_load(emulator)
+                {
+                    Ok(()) => println!("Last load undone"),
+                    Err(err) => println!("Cannot undo: {}", err),
+                },
+                Hotkey::SlotPicker => picker = match picker.take()
+                {
+                    Some(open) => { open.close(); None },
+                    None => Some(SlotPicker::open(&slots[focus])),
+                },
+                Hotkey::Menu => paused = !paused,
+                Hotkey::FastForward => (),
+            }
+        }
 
-            // RENDER
-            gb_texture.update(&gb_image);
-            draw_texture(gb_texture, 0.0, 0.0, WHITE);
+        // SLOT PICKER: ARROWS TO SELECT, ENTER TO LOAD, SHIFT + ENTER TO SAVE
+        if let Some(open) = picker.as_mut()
+        {
+            let columns = SLOT_COUNT / 2;
+            if is_key_pressed(KeyCode::Right) { open.selected = (open.selected + 1) % SLOT_COUNT; }
+            if is_key_pressed(KeyCode::Left) { open.selected = (open.selected + SLOT_COUNT - 1) % SLOT_COUNT; }
+            if is_key_pressed(KeyCode::Down) || is_key_pressed(KeyCode::Up) { open.selected = (open.selected + columns) % SLOT_COUNT; }
+            if is_key_pressed(KeyCode::Enter)
+            {
+                let slot = open.selected;
+                if shift
+                {
+                    save_slot(&mut slots[focus], slot, &mut machine.emulators()[focus]);
+                }
+                else
+                {
+                    load_slot(&mut slots[focus], slot, &mut machine.emulators()[focus]);
+                }
+                if let Some(open) = picker.take()
+                {
+                    open.close();
+                }
+            }
+        }
 
+        // CONSOLE
+        while let Ok(line) = console.try_recv()
+        {
+            console_command(&line, &mut search, &mut machine.emulators()[0], &cheat_file);
+        }
 
-            // WAIT
-            let elapsed_time = start_time.elapsed().unwrap().as_nanos() as u64;
-            if elapsed_time < TARGET_WAIT_TIME
+        // EMULATION
+        match audio.as_mut()
+        {
+            _ if paused || picker.is_some() => start_time = SystemTime::now(),
+            _ if fast_forward =>
+            {
+                // NO WAIT, THE AUDIO BUFFER OVERFLOWS AND DROPS THE EXTRA SAMPLES
+                for _ in 0..FAST_FORWARD_FRAMES
+                {
+                    machine.run_frame();
+                    outputs.frame_done(&mut machine, audio.as_mut());
+                }
+                start_time = SystemTime::now();
+            },
+            Some(output) if audio_sync =>
+            {
+                // RUN UNTIL THE AUDIO BUFFER IS FILLED, THE AUDIO DEVICE SETS THE SPEED
+                let mut frames = 0;
+                while output.buffered() < AUDIO_TARGET && frames < MAX_FRAMES_PER_UPDATE
+                {
+                    machine.run_frame();
+                    outputs.frame_done(&mut machine, Some(&mut *output));
+                    frames += 1;
+                }
+                output.follow_buffer_level(AUDIO_TARGET);
+            },
+            _ =>
             {
-                let sleep_time = Duration::from_nanos(TARGET_WAIT_TIME - elapsed_time);
-                std::thread::sleep(sleep_time);
+                machine.run_frame();
+                outputs.frame_done(&mut machine, audio.as_mut());
+
+                // WAIT
+                let elapsed_time = start_time.elapsed().unwrap().as_nanos() as u64;
+                if elapsed_time < FRAME_TIME
+                {
+                    let sleep_time = Duration::from_nanos(FRAME_TIME - elapsed_time);
+                    std::thread::sleep(sleep_time);
+                }
+                start_time = SystemTime::now(); 
+            },
+        }
+
+        // RENDER
+        for (i, (gb_image, gb_texture)) in screens.iter_mut().enumerate()
+        {
+            // A SAVE STATE OF ANOTHER MODEL CAN CHANGE THE SCREEN SIZE (SGB BORDER)
+            let size = machine.emulators()[i].screen_size();
+            if size != (gb_image.width as usize, gb_image.height as usize)
+            {
+                gb_texture.delete();
+                (*gb_image, *gb_texture) = new_screen(size.0, size.1);
+                (width, height) = size;
+                request_new_screen_size((width * count) as f32, height as f32);
+            }
+            gb_image.bytes.copy_from_slice(machine.emulators()[i].get_framebuffer());
+            gb_texture.update(gb_image);
+            draw_texture(*gb_texture, (i * width) as f32, 0.0, WHITE);
+        }
+        if let Some(open) = picker.as_ref()
+        {
+            open.draw((focus * width) as f32, width as f32, height as f32);
+        }
+        else if paused
+        {
+            // MENU
+            draw_rectangle(0.0, 0.0, (width * count) as f32, height as f32, Color::new(0.0, 0.0, 0.0, 0.6));
+            draw_text("PAUSED", 8.0, 20.0, 24.0, WHITE);
+            draw_text("Menu: resume - Esc: quit", 8.0, 40.0, 16.0, WHITE);
+
+            // CHEATS OF THE FIRST EMULATOR, UP/DOWN TO SELECT AND ENTER TO SWITCH ON/OFF
+            let cheats = &mut machine.emulators()[0].mem_bus.cheats;
+            if !cheats.list.is_empty()
+            {
+                let count = cheats.list.len();
+                if is_key_pressed(KeyCode::Down) { selected_cheat = (selected_cheat + 1) % count; }
+                if is_key_pressed(KeyCode::Up) { selected_cheat = (selected_cheat + count - 1) % count; }
+                selected_cheat = selected_cheat.min(count - 1);
+                if is_key_pressed(KeyCode::Enter)
+                {
+                    let enabled = !cheats.list[selected_cheat].enabled;
+                    cheats.set_enabled(selected_cheat, enabled);
+                    if let Err(err) = cheats.save(&cheat_file)
+                    {
+                        println!("Cannot save {}: {}", cheat_file, err);
+                    }
+                }
+                draw_text("Cheats (Enter: on/off)", 8.0, 60.0, 16.0, WHITE);
+                for (i, cheat) in cheats.list.iter().enumerate()
+                {
+                    let line = format!("{} [{}] {}", if i == selected_cheat { ">" } else { " " }, if cheat.enabled { "x" } else { " " }, cheat.name);
+                    draw_text(&line, 8.0, 76.0 + 14.0 * i as f32, 14.0, WHITE);
+                }
             }
-            start_time = SystemTime::now(); 
+        }
+
+        // UPDATE
+        next_frame().await;
 
-            // UPDATE
-            next_frame().await
-        } 
+        // 1-4 MUTE A CHANNEL, SHIFT + 1-4 SOLO IT
+        let apu = &mut machine.emulators()[0].mem_bus.apu;
+        for (channel, key) in CHANNEL_KEYS.iter().enumerate()
+        {
+            if is_key_pressed(*key)
+            {
+                if shift
+                {
+                    apu.solo = if apu.solo == Some(channel) { None } else { Some(channel) };
+                }
+                else
+                {
+                    apu.muted[channel] = !apu.muted[channel];
+                }
+            }
+        }
 
         // CHECK IF ESC
         if is_key_down(KeyCode::Escape)
@@ -88,4 +832,188 @@ async fn main()
             break;
         }
     }
+    outputs.finish();
+}
+
+// Blank screen image and its texture
+fn new_screen(width : usize, height : usize) -> (Image, Texture2D)
+{
+    let gb_image = Image{
+        width : width as u16,
+        height : height as u16,
+        bytes : vec![255; width * height * 4],
+    };
+    let gb_texture = Texture2D::from_image(&gb_image);
+    (gb_image, gb_texture)
+}
+
+fn save_slot(slots : &mut SaveSlots, slot : usize, emulator : &mut Emulator)
+{
+    match slots.save(slot, emulator)
+    {
+        Ok(()) => println!("State saved to slot {} ({})", slot + 1, slots.path(slot).display()),
+        Err(err) => println!("Cannot save slot {}: {}", slot + 1, err),
+    }
+}
+
+fn load_slot(slots : &mut SaveSlots, slot : usize, emulator : &mut Emulator)
+{
+    match slots.load(slot, emulator)
+    {
+        Ok(()) => println!("State loaded from slot {}", slot + 1),
+        Err(err) => println!("Cannot load slot {}: {}", slot + 1, err),
+    }
+}
+
+// Overlay showing the thumbnails of the slots of the focused emulator
+struct SlotPicker
+{
+    selected : usize,
+    slots : Vec<Option<(SlotInfo, Texture2D)>>,     // EMPTY SLOTS ARE None
+}
+
+impl SlotPicker
+{
+    fn open(slots : &SaveSlots) -> SlotPicker
+    {
+        SlotPicker
+        {
+            selected : slots.current,
+            slots : (0..SLOT_COUNT).map(|slot| slots.info(slot).map(|info|
+            {
+                let texture = Texture2D::from_rgba8(info.width as u16, info.height as u16, &info.thumbnail);
+                (info, texture)
+            })).collect(),
+        }
+    }
+
+    fn close(self)
+    {
+        for (_, texture) in self.slots.into_iter().flatten()
+        {
+            texture.delete();
+        }
+    }
+
+    // 2 ROWS OF 5 THUMBNAILS UNDER THE TIME OF THE SELECTED SLOT
+    fn draw(&self, x : f32, width : f32, height : f32)
+    {
+        const TOP: f32 = 34.0;
+        let columns = SLOT_COUNT / 2;
+        draw_rectangle(x, 0.0, width, height, Color::new(0.0, 0.0, 0.0, 0.8));
+        let title = match &self.slots[self.selected]
+        {
+            Some((info, _)) => format!("Slot {} - {}", self.selected + 1, slots::format_timestamp(info.timestamp)),
+            None => format!("Slot {} - empty", self.selected + 1),
+        };
+        draw_text(&title, x + 4.0, 14.0, 16.0, WHITE);
+        draw_text("Enter: load - Shift+Enter: save", x + 4.0, 28.0, 12.0, WHITE);
+
+        let (cell_width, cell_height) = (width / columns as f32, (height - TOP) / 2.0);
+        for (slot, content) in self.slots.iter().enumerate()
+        {
+            let cell_x = x + (slot % columns) as f32 * cell_width;
+            let cell_y = TOP + (slot / columns) as f32 * cell_height;
+            let (thumb_width, thumb_height) = (cell_width - 4.0, (cell_width - 4.0) * height / width);
+            let thumb_y = cell_y + (cell_height - thumb_height) / 2.0;
+            match content
+            {
+                Some((_, texture)) => draw_texture_ex(*texture, cell_x + 2.0, thumb_y, WHITE, DrawTextureParams
+                {
+                    dest_size : Some(vec2(thumb_width, thumb_height)),
+                    ..Default::default()
+                }),
+                None => draw_rectangle(cell_x + 2.0, thumb_y, thumb_width, thumb_height, DARKGRAY),
+            }
+            draw_text(&(slot + 1).to_string(), cell_x + 3.0, thumb_y + 10.0, 12.0, WHITE);
+            if slot == self.selected
+            {
+                draw_rectangle_lines(cell_x + 1.0, thumb_y - 1.0, thumb_width + 2.0, thumb_height + 2.0, 2.0, YELLOW);
+            }
+        }
+    }
+}
+
+// Lines typed in the terminal, read by a thread
+fn spawn_console() -> std::sync::mpsc::Receiver<String>
+{
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move ||
+    {
+        for line in std::io::stdin().lines().map_while(Result::ok)
+        {
+            if sender.send(line).is_err()
+            {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// RAM search on the first emulator:
+//     search [8|16] [signed]       new search, every address is a candidate
+//     equal | changed | increased | decreased | value <n>
+//     list                         remaining addresses and values
+//     cheat <address> <value> [name]   add a GameShark cheat writing the value
+fn console_command(line : &str, search : &mut Option<RamSearch>, emulator : &mut Emulator, cheat_file : &str)
+{
+    const LIST_LIMIT: usize = 50;
+    let words : Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice()
+    {
+        [] => (),
+        ["search", options @ ..] =>
+        {
+            let width = if options.contains(&"16") { Width::Word } else { Width::Byte };
+            let new_search = RamSearch::start(&emulator.mem_bus, width, options.contains(&"signed"));
+            println!("{} candidates", new_search.candidates.len());
+            *search = Some(new_search);
+        },
+        ["list"] => match search
+        {
+            Some(search) =>
+            {
+                for (address, value) in search.results().iter().take(LIST_LIMIT)
+                {
+                    println!("0x{:04X}: {}", address, value);
+                }
+                if search.candidates.len() > LIST_LIMIT
+                {
+                    println!("... {} more", search.candidates.len() - LIST_LIMIT);
+                }
+            },
+            None => println!("No search, start one with \"search\""),
+        },
+        ["cheat", address, value, name @ ..] =>
+        {
+            let width = search.as_ref().map_or(Width::Byte, |s| s.width);
+            let (Some(address), Some(value)) = (search::parse_number(address).and_then(|a| u16::try_from(a).ok()), search::parse_number(value)) else
+            {
+                println!("Expected \"cheat <address> <value> [name]\"");
+                return;
+            };
+            let codes = search::gameshark_codes(&emulator.mem_bus, address, value, width);
+            let cheats = &mut emulator.mem_bus.cheats;
+            let added = codes.and_then(|codes| codes.iter().try_for_each(|code| cheats.add(code, &name.join(" "), true)).map(|_| codes));
+            match added
+            {
+                Ok(codes) =>
+                {
+                    println!("Added {}", codes.join(" "));
+                    if let Err(err) = emulator.mem_bus.cheats.save(cheat_file)
+                    {
+                        println!("Cannot save {}: {}", cheat_file, err);
+                    }
+                },
+                Err(err) => println!("{}", err),
+            }
+        },
+        filter => match (search.as_mut(), Filter::from_words(filter))
+        {
+            (Some(search), Some(filter)) => println!("{} candidates", search.filter(&emulator.mem_bus, filter)),
+            (None, Some(_)) => println!("No search, start one with \"search\""),
+            _ => println!("Unknown command: {}", line),
+        },
+    }
 }
@@ -0,0 +1,377 @@
+use std::fs;
+use std::path::Path;
+use crate::cartridge::{RomError, MAX_ROM_SIZE};
+
+// Soft patching: a patch found next to the ROM (<rom>.ips, <rom>.ups or <rom>.bps) is applied
+// to the ROM data in memory, the ROM file is never modified.
+
+// Patch formats, tried in this order
+const EXTENSIONS : [&str; 3] = ["ips", "ups", "bps"];
+
+const IPS_EOF : u32 = 0x454F46;		// "EOF"
+
+// Apply the patch next to the ROM if there is one, returns the file name of the applied patch
+pub fn apply_patch_for(path : &str, rom : &mut Vec<u8>) -> Result<Option<String>, RomError>
+{
+	for extension in EXTENSIONS
+	{
+		let patch_path = Path::new(path).with_extension(extension);
+		let Ok(patch) = fs::read(&patch_path) else { continue };
+		let patch_name = patch_path.display().to_string();
+		let patched = match extension
+		{
+			"ips" => apply_ips(rom, &patch),
+			"ups" => apply_ups(rom, &patch),
+			_ => apply_bps(rom, &patch),
+		};
+		*rom = patched.map_err(|reason| RomError::Patch(format!("{}: {}", patch_name, reason)))?;
+		return Ok(Some(patch_name));
+	}
+	Ok(None)
+}
+
+// Bounds checked reader of the patch data
+struct PatchReader<'a>
+{
+	data : &'a [u8],
+	position : usize,
+}
+
+impl PatchReader<'_>
+{
+	fn bytes(&mut self, count : usize) -> Result<&[u8], String>
+	{
+		let end = self.position + count;
+		if end > self.data.len()
+		{
+			return Err("unexpected end of the patch".to_string());
+		}
+		let bytes = &self.data[self.position..end];
+		self.position = end;
+		Ok(bytes)
+	}
+
+	fn byte(&mut self) -> Result<u8, String>
+	{
+		Ok(self.bytes(1)?[0])
+	}
+
+	// Big endian number (IPS)
+	fn number(&mut self, size : usize) -> Result<u32, String>
+	{
+		Ok(self.bytes(size)?.iter().fold(0, |value, &byte| value << 8 | byte as u32))
+	}
+
+	// Variable length number (UPS and BPS): 7 bits per byte, the last byte has bit 7 set
+	fn varint(&mut self) -> Result<usize, String>
+	{
+		let mut value : usize = 0;
+		let mut shift : usize = 1;
+		loop
+		{
+			let byte = self.byte()?;
+			value = value.checked_add((byte & 0x7F) as usize * shift).ok_or("invalid number")?;
+			if byte & 0x80 != 0
+			{
+				return Ok(value);
+			}
+			shift = shift.checked_mul(128).ok_or("invalid number")?;
+			value = value.checked_add(shift).ok_or("invalid number")?;
+		}
+	}
+}
+
+// IPS: records of offset (3 bytes) + size (2 bytes) + data, a size of 0 is a run of one byte.
+// No checksums, an optional size after "EOF" truncates the ROM.
+fn apply_ips(rom : &[u8], patch : &[u8]) -> Result<Vec<u8>, String>
+{
+	let mut reader = PatchReader { data : patch, position : 0 };
+	if reader.bytes(5)? != b"PATCH"
+	{
+		return Err("not an IPS patch".to_string());
+	}
+
+	let mut target = rom.to_vec();
+	loop
+	{
+		let offset = reader.number(3)?;
+		if offset == IPS_EOF
+		{
+			break;
+		}
+		let offset = offset as usize;
+		let size = reader.number(2)? as usize;
+		let (size, run) = if size == 0 { (reader.number(2)? as usize, Some(reader.byte()?)) } else { (size, None) };
+		if offset + size > MAX_ROM_SIZE
+		{
+			return Err(too_big());
+		}
+		if target.len() < offset + size
+		{
+			target.resize(offset + size, 0);
+		}
+		match run
+		{
+			Some(value) => target[offset..offset + size].fill(value),
+			None => target[offset..offset + size].copy_from_slice(reader.bytes(size)?),
+		}
+	}
+	if let Ok(size) = reader.number(3)
+	{
+		target.truncate(size as usize);
+	}
+	Ok(target)
+}
+
+// Checksums at the end of UPS and BPS patches: source, target and patch CRC32 (little endian)
+fn check_footer(patch : &[u8], source : &[u8]) -> Result<u32, String>
+{
+	if patch.len() < 12
+	{
+		return Err("unexpected end of the patch".to_string());
+	}
+	let footer = &patch[patch.len() - 12..];
+	let crc = |i : usize| u32::from_le_bytes(footer[i..i + 4].try_into().unwrap());
+	if crc32fast::hash(&patch[..patch.len() - 4]) != crc(8)
+	{
+		return Err("the patch is damaged (bad checksum)".to_string());
+	}
+	if crc32fast::hash(source) != crc(0)
+	{
+		return Err("the patch is made for another ROM (source checksum mismatch)".to_string());
+	}
+	Ok(crc(4))
+}
+
+// A damaged patch can ask for any size, checked before allocating
+fn too_big() -> String
+{
+	format!("the patched ROM would be bigger than {} MB", MAX_ROM_SIZE / (1024 * 1024))
+}
+
+fn check_target(target : &[u8], expected : u32) -> Result<(), String>
+{
+	if crc32fast::hash(target) != expected
+	{
+		return Err("the patched ROM is wrong (target checksum mismatch)".to_string());
+	}
+	Ok(())
+}
+
+// UPS: the target is the source XORed with the patch data, each hunk starts after a relative offset
+// and ends at a 0 byte
+fn apply_ups(rom : &[u8], patch : &[u8]) -> Result<Vec<u8>, String>
+{
+	let target_crc = check_footer(patch, rom)?;
+	let mut reader = PatchReader { data : &patch[..patch.len() - 12], position : 0 };
+	if reader.bytes(4)? != b"UPS1"
+	{
+		return Err("not a UPS patch".to_string());
+	}
+	let source_size = reader.varint()?;
+	let target_size = reader.varint()?;
+	if source_size != rom.len()
+	{
+		return Err("the patch is made for another ROM (size mismatch)".to_string());
+	}
+	if target_size > MAX_ROM_SIZE
+	{
+		return Err(too_big());
+	}
+
+	// BYTES PAST THE END OF THE SOURCE ARE 0
+	let mut target = rom.to_vec();
+	target.resize(target_size, 0);
+	let mut offset = 0;
+	while reader.position < reader.data.len()
+	{
+		offset += reader.varint()?;
+		loop
+		{
+			let xor = reader.byte()?;
+			if let Some(byte) = target.get_mut(offset)
+			{
+				*byte ^= xor;
+			}
+			offset += 1;
+			if xor == 0
+			{
+				break;
+			}
+		}
+	}
+	check_target(&target, target_crc)?;
+	Ok(target)
+}
+
+// BPS: the target is built with commands copying from the source, the patch, or the target itself
+fn apply_bps(rom : &[u8], patch : &[u8]) -> Result<Vec<u8>, String>
+{
+	let target_crc = check_footer(patch, rom)?;
+	let mut reader = PatchReader { data : &patch[..patch.len() - 12], position : 0 };
+	if reader.bytes(4)? != b"BPS1"
+	{
+		return Err("not a BPS patch".to_string());
+	}
+	let source_size = reader.varint()?;
+	let target_size = reader.varint()?;
+	let metadata_size = reader.varint()?;
+	reader.bytes(metadata_size)?;
+	if source_size != rom.len()
+	{
+		return Err("the patch is made for another ROM (size mismatch)".to_string());
+	}
+	if target_size > MAX_ROM_SIZE
+	{
+		return Err(too_big());
+	}
+
+	let out_of_range = || "the patch reads outside of the ROM".to_string();
+	let mut target : Vec<u8> = Vec::with_capacity(target_size);
+	let mut source_offset : isize = 0;
+	let mut target_offset : isize = 0;
+	while reader.position < reader.data.len()
+	{
+		let command = reader.varint()?;
+		let length = (command >> 2) + 1;
+		if target.len() + length > target_size
+		{
+			return Err("the patch writes past the end of the patched ROM".to_string());
+		}
+		match command & 0x03
+		{
+			// SOURCE READ: SAME PLACE IN THE SOURCE
+			0 =>
+			{
+				let start = target.len();
+				target.extend_from_slice(rom.get(start..start + length).ok_or_else(out_of_range)?);
+			},
+			// TARGET READ: BYTES OF THE PATCH
+			1 => target.extend_from_slice(reader.bytes(length)?),
+			// SOURCE COPY / TARGET COPY: FROM A RELATIVE OFFSET, SIGN IN BIT 0
+			kind =>
+			{
+				let data = reader.varint()?;
+				let delta = if data & 1 != 0 { -((data >> 1) as isize) } else { (data >> 1) as isize };
+				if kind == 2
+				{
+					source_offset += delta;
+					let start = usize::try_from(source_offset).map_err(|_| out_of_range())?;
+					target.extend_from_slice(rom.get(start..start + length).ok_or_else(out_of_range)?);
+					source_offset += length as isize;
+				}
+				else
+				{
+					// THE COPY CAN OVERLAP THE BYTES IT WRITES, ONE BYTE AT A TIME
+					target_offset += delta;
+					for _ in 0..length
+					{
+						let index = usize::try_from(target_offset).map_err(|_| out_of_range())?;
+						let byte = *target.get(index).ok_or_else(out_of_range)?;
+						target.push(byte);
+						target_offset += 1;
+					}
+				}
+			},
+		}
+	}
+	if target.len() != target_size
+	{
+		return Err("the patched ROM has the wrong size".to_string());
+	}
+	check_target(&target, target_crc)?;
+	Ok(target)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// UPS/BPS number: 7 bits per byte, bit 7 set on the last byte
+	fn varint(mut value : usize) -> Vec<u8>
+	{
+		let mut bytes = Vec::new();
+		loop
+		{
+			let low = (value & 0x7F) as u8;
+			value >>= 7;
+			if value == 0
+			{
+				bytes.push(0x80 | low);
+				return bytes;
+			}
+			bytes.push(low);
+			value -= 1;
+		}
+	}
+
+	// Source, target and patch checksums
+	fn with_footer(mut patch : Vec<u8>, source : &[u8], target : &[u8]) -> Vec<u8>
+	{
+		patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+		patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+		let crc = crc32fast::hash(&patch);
+		patch.extend_from_slice(&crc.to_le_bytes());
+		patch
+	}
+
+	#[test]
+	fn ips_run_record()
+	{
+		let patch = b"PATCH\x00\x00\x02\x00\x00\x00\x03\xAAEOF";
+		assert_eq!(apply_ips(&[0; 8], patch).unwrap(), [0, 0, 0xAA, 0xAA, 0xAA, 0, 0, 0]);
+	}
+
+	#[test]
+	fn ips_truncation_after_eof()
+	{
+		let patch = b"PATCH\x00\x00\x01\x00\x01\x55EOF\x00\x00\x04";
+		assert_eq!(apply_ips(&[0; 8], patch).unwrap(), [0, 0x55, 0, 0]);
+	}
+
+	#[test]
+	fn ups_target_checksum_mismatch()
+	{
+		// XOR 0x01 ON THE FIRST BYTE, THE FOOTER EXPECTS THE SOURCE UNCHANGED
+		let source = [0x10, 0x20, 0x30, 0x40];
+		let mut patch = b"UPS1".to_vec();
+		patch.extend(varint(4));
+		patch.extend(varint(4));
+		patch.extend(varint(0));
+		patch.extend([0x01, 0x00]);
+		let patch = with_footer(patch, &source, &source);
+		assert!(apply_ups(&source, &patch).unwrap_err().contains("target checksum mismatch"));
+	}
+
+	#[test]
+	fn bps_target_checksum_mismatch()
+	{
+		let source = [0x10, 0x20, 0x30, 0x40];
+		let mut patch = b"BPS1".to_vec();
+		patch.extend(varint(4));
+		patch.extend(varint(4));
+		patch.extend(varint(0));
+		patch.extend(varint((4 - 1) << 2 | 1));		// TARGET READ OF 4 BYTES
+		patch.extend([1, 2, 3, 4]);
+		let patch = with_footer(patch, &source, &source);
+		assert!(apply_bps(&source, &patch).unwrap_err().contains("target checksum mismatch"));
+	}
+
+	#[test]
+	fn bps_overlapping_target_copy()
+	{
+		// "AB" THEN A TARGET COPY OF 4 BYTES FROM OFFSET 0 REPEATS IT
+		let source = *b"AB";
+		let target = *b"ABABAB";
+		let mut patch = b"BPS1".to_vec();
+		patch.extend(varint(2));
+		patch.extend(varint(6));
+		patch.extend(varint(0));
+		patch.extend(varint((2 - 1) << 2));			// SOURCE READ OF 2 BYTES
+		patch.extend(varint((4 - 1) << 2 | 3));		// TARGET COPY OF 4 BYTES
+		patch.extend(varint(0));
+		let patch = with_footer(patch, &source, &target);
+		assert_eq!(apply_bps(&source, &patch).unwrap(), target);
+	}
+}
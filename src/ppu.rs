@@ -1,76 +1,382 @@
-pub struct PPU 
+use crate::bus::*;
+use crate::savestate::{StateIo, StateError};
+
+// LCDC / STAT / LY register addresses
+pub const LCDC : u16 = 0xFF40;
+pub const STAT : u16 = 0xFF41;
+pub const LY : u16 = 0xFF44;
+pub const LYC : u16 = 0xFF45;
+
+// DMG shades from white to black
+pub const DMG_SHADES : [[u8; 3]; 4] =
+[
+	[0xFF, 0xFF, 0xFF],
+	[0xAA, 0xAA, 0xAA],
+	[0x55, 0x55, 0x55],
+	[0x00, 0x00, 0x00],
+];
+
+pub struct PPU
 {
 	mode: u8, 			//Mode 0: HBlank, 1: VBlank, 2: OAM Scan, 3: Drawing Pixels
 	mode_cycle : u32,	//Cycle in the current mode
 	ly: u8, 			//Current line
-	lyc: u8, 			//LYC register: if LY == LYC, then an interrupt is triggered
-	stat: u8, 			//STAT register
+	lcd_on: bool,		//LCDC bit 7 as seen at the last step
+	first_line: bool,	//First line after the LCD was switched on (reports mode 0 instead of mode 2)
+	skip_frame: bool,	//First frame after the LCD was switched on is not displayed
+	stat_line: bool,	//Internal STAT interrupt line, the interrupt is requested on a rising edge
+	window_line: u8,	//Internal window line counter, only incremented on lines where the window is drawn
+
+	pub dmg_palettes: [[[u8; 3]; 4]; 3],	//Colors of the 4 DMG shades for BGP, OBP0 and OBP1
 
 	framebuffer: [u8; 160 * 144 * 4],	//Framebuffer for the current frame
 	scanline: [u8; 160 * 4], 			//Scanline buffer for the current line
+	shades: [u8; 160 * 144],			//DMG shade (0-3) of each pixel, used by the SGB to colorize the screen
+	shade_line: [u8; 160],				//Shades of the current line
 }
 
 impl PPU {
     pub fn init_ppu() -> Self {
         PPU {
-            mode: 2,    
+            mode: 2,
             mode_cycle: 0,
             ly: 0,
-            lyc: 0,
-            stat: 0,
-            framebuffer: [0; 160 * 144 * 4],
-            scanline: [0; 160 * 4],        
+            lcd_on: false,
+            first_line: false,
+            skip_frame: false,
+            stat_line: false,
+            window_line: 0,
+            dmg_palettes: [DMG_SHADES; 3],
+            framebuffer: [0xFF; 160 * 144 * 4],
+            scanline: [0xFF; 160 * 4],
+            shades: [0; 160 * 144],
+            shade_line: [0; 160],
         }
     }
 
-	pub fn step(&mut self, cycles: u32) {
+    pub fn get_framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn get_shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        self.mode == 1
+    }
+
+    pub fn sync_state(&mut self, state: &mut StateIo) {
+        state.u8_upto(&mut self.mode, 3);
+        state.u32(&mut self.mode_cycle);
+        state.u8_upto(&mut self.ly, 153);
+        state.bool(&mut self.lcd_on);
+        state.bool(&mut self.first_line);
+        state.bool(&mut self.skip_frame);
+        state.bool(&mut self.stat_line);
+        state.u8(&mut self.window_line);
+        state.bytes(&mut self.framebuffer);
+        state.bytes(&mut self.shades);
+        if self.shades.iter().any(|&shade| shade > 3) {
+            state.fail(StateError::BadValue("DMG shade above 3".to_string()));
+        }
+
+        // VERSION 2: COMPATIBILITY PALETTES, THEY CAN COME FROM THE BUTTONS HELD AT BOOT
+        if state.version >= 2 {
+            for color in self.dmg_palettes.iter_mut().flatten() {
+                state.bytes(color);
+            }
+        }
+    }
+
+	pub fn step(&mut self, cycles: u32, mem_bus: &mut MemoryBus) {
+        let lcdc = mem_bus.io_registers[(LCDC - 0xFF00) as usize];
+
+        // LCD OFF: THE PPU IS STOPPED, NOTHING TO DO UNTIL LCDC BIT 7 IS SET AGAIN
+        if lcdc & 0x80 == 0 {
+            if self.lcd_on {
+                self.power_off(mem_bus);
+            }
+            return;
+        }
+        if !self.lcd_on {
+            self.power_on(mem_bus);
+        }
+
         self.mode_cycle += cycles;
 
         match self.mode {
             2 => { // OAM Scan
                 if self.mode_cycle >= 80 {
-                    self.mode_cycle = 0;
+                    self.mode_cycle -= 80;
                     self.mode = 3;
+                    self.first_line = false;
                 }
             }
             3 => { // Drawing pixels
                 if self.mode_cycle >= 172 {
-                    self.mode_cycle = 0;
+                    self.mode_cycle -= 172;
                     self.mode = 0;
+                    self.render_scanline(mem_bus);
                     self.copy_scanline();
+                    mem_bus.hblank_dma();
                 }
             }
             0 => { // HBlank
                 if self.mode_cycle >= 204 {
-                    self.mode_cycle = 0;
+                    self.mode_cycle -= 204;
                     self.ly += 1;
 
                     if self.ly == 144 {
                         self.mode = 1;   // Enter VBlank
-                        // ! TODO: request VBlank interrupt here
+                        mem_bus.request_interrupt(INT_VBLANK);
+                        self.skip_frame = false;
                     } else {
-                        self.mode = 2;  
+                        self.mode = 2;
                     }
                 }
             }
             1 => { // VBlank
                 if self.mode_cycle >= 456 {
-                    self.mode_cycle = 0;
+                    self.mode_cycle -= 456;
                     self.ly += 1;
 
                     if self.ly > 153 {
                         self.ly = 0;
+                        self.window_line = 0;
                         self.mode = 2;
                     }
                 }
             }
             _ => ()
         }
+
+        self.update_registers(mem_bus);
+    }
+
+    // Clearing LCDC bit 7 stops the PPU: LY is reset to 0, STAT reports mode 0,
+    // the screen goes blank and the CPU gets free access to VRAM and OAM
+    fn power_off(&mut self, mem_bus: &mut MemoryBus) {
+        self.lcd_on = false;
+        self.mode = 0;
+        self.mode_cycle = 0;
+        self.ly = 0;
+        self.stat_line = false;
+        self.framebuffer.fill(0xFF);
+        self.shades.fill(0);
+        mem_bus.io_registers[(LY - 0xFF00) as usize] = 0;
+        mem_bus.io_registers[(STAT - 0xFF00) as usize] &= !0x03;
+    }
+
+    // Setting LCDC bit 7 restarts the PPU at the beginning of line 0. The first
+    // line skips the OAM scan (STAT reads mode 0) and the first frame is not shown.
+    fn power_on(&mut self, mem_bus: &mut MemoryBus) {
+        self.lcd_on = true;
+        self.mode = 2;
+        self.mode_cycle = 0;
+        self.ly = 0;
+        self.window_line = 0;
+        self.first_line = true;
+        self.skip_frame = true;
+        self.update_registers(mem_bus);
+    }
+
+    // Mirror the internal state into LY and STAT and request the STAT interrupt
+    fn update_registers(&mut self, mem_bus: &mut MemoryBus) {
+        let lyc = mem_bus.io_registers[(LYC - 0xFF00) as usize];
+        let mut stat = mem_bus.io_registers[(STAT - 0xFF00) as usize] & 0x78;
+
+        stat |= if self.first_line { 0 } else { self.mode };
+        if self.ly == lyc {
+            stat |= 0x04;
+        }
+
+        let stat_line = (stat & 0x44 == 0x44)
+            || (stat & 0x08 != 0 && stat & 0x03 == 0)
+            || (stat & 0x10 != 0 && stat & 0x03 == 1)
+            || (stat & 0x20 != 0 && stat & 0x03 == 2);
+        if stat_line && !self.stat_line {
+            mem_bus.request_interrupt(INT_STAT);
+        }
+        self.stat_line = stat_line;
+
+        mem_bus.io_registers[(STAT - 0xFF00) as usize] = 0x80 | stat;
+        mem_bus.io_registers[(LY - 0xFF00) as usize] = self.ly;
+    }
+
+    fn render_scanline(&mut self, mem_bus: &MemoryBus) {
+        let lcdc = mem_bus.io_registers[(LCDC - 0xFF00) as usize];
+        let mut bg_colors = [0u8; 160];       // Color index of the BG/window pixel, 0 lets sprites through
+        let mut bg_priority = [false; 160];   // CGB BG map attribute bit 7
+
+        // On DMG, LCDC bit 0 disables BG and window. On CGB it only removes their priority over sprites.
+        if mem_bus.cgb_mode || lcdc & 0x01 != 0 {
+            self.render_background(mem_bus, lcdc, &mut bg_colors, &mut bg_priority);
+        } else {
+            for x in 0..160 {
+                self.put_dmg_pixel(x, 0, 0);
+            }
+        }
+
+        if lcdc & 0x02 != 0 {
+            self.render_sprites(mem_bus, lcdc, &bg_colors, &bg_priority);
+        }
+    }
+
+    fn render_background(&mut self, mem_bus: &MemoryBus, lcdc: u8, bg_colors: &mut [u8; 160], bg_priority: &mut [bool; 160]) {
+        let scy = mem_bus.io_registers[0x42];
+        let scx = mem_bus.io_registers[0x43];
+        let bgp = mem_bus.io_registers[0x47];
+        let wy = mem_bus.io_registers[0x4A];
+        let wx = mem_bus.io_registers[0x4B] as usize;
+        let window = lcdc & 0x20 != 0 && self.ly >= wy && wx <= 166;
+        let mut window_drawn = false;
+
+        for x in 0..160 {
+            let (map, px, py) = if window && x + 7 >= wx {
+                window_drawn = true;
+                (if lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 }, (x + 7 - wx) as u8, self.window_line)
+            } else {
+                (if lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 }, (x as u8).wrapping_add(scx), self.ly.wrapping_add(scy))
+            };
+
+            let map_address = map + (py as usize / 8) * 32 + px as usize / 8;
+            let tile = mem_bus.vram[0][map_address];
+            let attributes = if mem_bus.cgb_mode { mem_bus.vram[1][map_address] } else { 0 };
+
+            let tile_address = if lcdc & 0x10 != 0 {
+                tile as usize * 16
+            } else {
+                (0x1000 + tile as i8 as i32 * 16) as usize
+            };
+            let row = if attributes & 0x40 != 0 { 7 - py % 8 } else { py % 8 };
+            let column = if attributes & 0x20 != 0 { 7 - px % 8 } else { px % 8 };
+            let bank = ((attributes >> 3) & 0x01) as usize;
+            let color = PPU::tile_pixel(&mem_bus.vram[bank], tile_address + row as usize * 2, column);
+
+            bg_colors[x] = color;
+            bg_priority[x] = attributes & 0x80 != 0;
+            if mem_bus.cgb_mode {
+                self.put_pixel(x, PPU::cgb_color(&mem_bus.bg_palette_ram, attributes & 0x07, color));
+            } else {
+                self.put_dmg_pixel(x, 0, PPU::dmg_shade(bgp, color));
+            }
+        }
+
+        if window_drawn {
+            self.window_line += 1;
+        }
+    }
+
+    fn render_sprites(&mut self, mem_bus: &MemoryBus, lcdc: u8, bg_colors: &[u8; 160], bg_priority: &[bool; 160]) {
+        let height: i16 = if lcdc & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.ly as i16;
+
+        // OAM scan: the first 10 sprites in OAM order overlapping the line
+        let mut sprites: Vec<usize> = (0..40)
+            .filter(|&i| {
+                let y = mem_bus.sprite_attrib_ram[i * 4] as i16 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(10)
+            .collect();
+
+        // DMG (and CGB with OPRI set): the smallest X wins, ties are broken by OAM order.
+        // CGB: OAM order only.
+        if !mem_bus.cgb_mode || mem_bus.io_registers[0x6C] & 0x01 != 0 {
+            sprites.sort_by_key(|&i| mem_bus.sprite_attrib_ram[i * 4 + 1]);
+        }
+
+        let mut drawn = [false; 160];
+        for i in sprites {
+            let y = mem_bus.sprite_attrib_ram[i * 4] as i16 - 16;
+            let x = mem_bus.sprite_attrib_ram[i * 4 + 1] as i16 - 8;
+            let mut tile = mem_bus.sprite_attrib_ram[i * 4 + 2] as usize;
+            let attributes = mem_bus.sprite_attrib_ram[i * 4 + 3];
+
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let mut row = ly - y;
+            if attributes & 0x40 != 0 {
+                row = height - 1 - row;
+            }
+            let bank = if mem_bus.cgb_mode { ((attributes >> 3) & 0x01) as usize } else { 0 };
+
+            for column in 0..8 {
+                let px = x + column;
+                if !(0..160).contains(&px) || drawn[px as usize] {
+                    continue;
+                }
+                let px = px as usize;
+                let bit = if attributes & 0x20 != 0 { 7 - column } else { column } as u8;
+                let color = PPU::tile_pixel(&mem_bus.vram[bank], tile * 16 + row as usize * 2, bit);
+                if color == 0 {
+                    continue;   // Transparent, a lower priority sprite may still be drawn here
+                }
+                drawn[px] = true;
+
+                let bg_wins = bg_colors[px] != 0 && if mem_bus.cgb_mode {
+                    lcdc & 0x01 != 0 && (attributes & 0x80 != 0 || bg_priority[px])
+                } else {
+                    attributes & 0x80 != 0
+                };
+                if bg_wins {
+                    continue;
+                }
+
+                if mem_bus.cgb_mode {
+                    self.put_pixel(px, PPU::cgb_color(&mem_bus.obj_palette_ram, attributes & 0x07, color));
+                } else {
+                    let palette = ((attributes >> 4) & 0x01) as usize;
+                    let obp = mem_bus.io_registers[0x48 + palette];
+                    self.put_dmg_pixel(px, 1 + palette, PPU::dmg_shade(obp, color));
+                }
+            }
+        }
+    }
+
+    // 2bpp tile row: bit 7 is the leftmost pixel, the second byte holds the high bits
+    fn tile_pixel(vram: &[u8; 0x2000], address: usize, column: u8) -> u8 {
+        let lo = (vram[address] >> (7 - column)) & 0x01;
+        let hi = (vram[address + 1] >> (7 - column)) & 0x01;
+        (hi << 1) | lo
+    }
+
+    fn dmg_shade(palette: u8, color: u8) -> usize {
+        ((palette >> (color * 2)) & 0x03) as usize
+    }
+
+    // CGB palettes hold 4 little-endian RGB555 colors
+    fn cgb_color(palette_ram: &[u8; 0x40], palette: u8, color: u8) -> [u8; 3] {
+        let index = palette as usize * 8 + color as usize * 2;
+        PPU::rgb555_to_rgb(palette_ram[index] as u16 | (palette_ram[index + 1] as u16) << 8)
+    }
+
+    pub fn rgb555_to_rgb(rgb555: u16) -> [u8; 3] {
+        let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        [expand(rgb555 & 0x1F), expand((rgb555 >> 5) & 0x1F), expand((rgb555 >> 10) & 0x1F)]
+    }
+
+    // slot: 0 for BGP, 1 for OBP0, 2 for OBP1
+    fn put_dmg_pixel(&mut self, x: usize, slot: usize, shade: usize) {
+        self.shade_line[x] = shade as u8;
+        self.put_pixel(x, self.dmg_palettes[slot][shade]);
+    }
+
+    fn put_pixel(&mut self, x: usize, rgb: [u8; 3]) {
+        self.scanline[x * 4..x * 4 + 3].copy_from_slice(&rgb);
+        self.scanline[x * 4 + 3] = 0xFF;
     }
 
     fn copy_scanline(&mut self) {
+        if self.skip_frame {
+            return;
+        }
         let start = self.ly as usize * 160 * 4;
         self.framebuffer[start..start + 160 * 4]
             .copy_from_slice(&self.scanline);
+        let start = self.ly as usize * 160;
+        self.shades[start..start + 160].copy_from_slice(&self.shade_line);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::io::BufWriter;
+use crate::serial::SerialLink;
+
+// Game Boy Printer, plugged as the serial partner. The game sends packets:
+//     0x88 0x33 | command | compression | length (LE) | data | checksum (LE) | 0x00 0x00
+// The printer answers 0x81 to the first trailing 0x00 and its status to the second one.
+
+const CMD_INIT : u8 = 0x01;
+const CMD_PRINT : u8 = 0x02;
+const CMD_DATA : u8 = 0x04;
+const CMD_STATUS : u8 = 0x0F;
+
+// Status bits
+const STATUS_CHECKSUM_ERROR : u8 = 0x01;
+const STATUS_PRINTING : u8 = 0x02;
+const STATUS_DATA_FULL : u8 = 0x04;			// Empty DATA packet received, ready to print
+const STATUS_UNPROCESSED : u8 = 0x08;		// Image data waiting for a PRINT command
+
+const PAPER_WIDTH : usize = 160;
+const TILES_PER_ROW : usize = PAPER_WIDTH / 8;
+const GRAY_SHADES : [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+pub struct Printer
+{
+	packet : Vec<u8>,					//Bytes of the packet being received
+	status : u8,
+	image : Vec<u8>,					//Tile data received since the last PRINT
+	paper : Vec<u8>,					//Gray pixels printed on the current sheet, 160 per line
+	output : String,					//PNG files are named <output>_<n>.png
+	prints : u32,
+}
+
+impl Printer
+{
+	pub fn init_printer(output : &str) -> Printer
+	{
+		Printer
+		{
+			packet : Vec::new(),
+			status : 0,
+			image : Vec::new(),
+			paper : Vec::new(),
+			output : output.to_string(),
+			prints : 0,
+		}
+	}
+
+	fn data_length(&self) -> usize
+	{
+		(self.packet[4] as usize) | (self.packet[5] as usize) << 8
+	}
+
+	// Packet complete (checksum received), the packet bytes are left in self.packet
+	fn process_packet(&mut self)
+	{
+		let length = self.data_length();
+		let checksum = self.packet[2..6 + length].iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+		let expected = (self.packet[6 + length] as u16) | (self.packet[7 + length] as u16) << 8;
+		if checksum != expected
+		{
+			self.status |= STATUS_CHECKSUM_ERROR;
+			return;
+		}
+		self.status &= !STATUS_CHECKSUM_ERROR;
+
+		let data = self.packet[6..6 + length].to_vec();
+		match self.packet[2]
+		{
+			CMD_INIT =>
+			{
+				self.image.clear();
+				self.status = 0;
+			},
+			CMD_DATA if length == 0 => self.status |= STATUS_DATA_FULL,
+			CMD_DATA =>
+			{
+				let compressed = self.packet[3] & 0x01 != 0;
+				self.image.extend(if compressed { decompress(&data) } else { data });
+				self.status |= STATUS_UNPROCESSED;
+			},
+			CMD_PRINT if length >= 4 =>
+			{
+				// SHEETS, MARGINS (BEFORE IN THE HIGH NIBBLE, AFTER IN THE LOW ONE), PALETTE, EXPOSURE
+				let margin_before = data[1] >> 4;
+				let margin_after = data[1] & 0x0F;
+				if margin_before != 0
+				{
+					self.cut_paper();
+				}
+				self.print(data[2]);
+				if margin_after != 0
+				{
+					self.cut_paper();
+				}
+				self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_DATA_FULL)) | STATUS_PRINTING;
+			},
+			_ => (),
+		}
+	}
+
+	// Print the received tiles with a palette (same format as BGP)
+	fn print(&mut self, palette : u8)
+	{
+		let rows = self.image.len() / (TILES_PER_ROW * 16);
+		for y in 0..rows * 8
+		{
+			for x in 0..PAPER_WIDTH
+			{
+				let tile = (y / 8) * TILES_PER_ROW + x / 8;
+				let offset = tile * 16 + (y % 8) * 2;
+				let bit = 7 - (x % 8);
+				let color = ((self.image[offset] >> bit) & 0x01) | ((self.image[offset + 1] >> bit) & 0x01) << 1;
+				self.paper.push(GRAY_SHADES[((palette >> (color * 2)) & 0x03) as usize]);
+			}
+		}
+		self.image.clear();
+	}
+
+	// A margin feeds the paper: the sheet printed so far is saved
+	fn cut_paper(&mut self)
+	{
+		if self.paper.is_empty()
+		{
+			return;
+		}
+		self.prints += 1;
+		let path = format!("{}_{}.png", self.output, self.prints);
+		match save_png(&path, &self.paper)
+		{
+			Ok(()) => println!("Printed {}", path),
+			Err(err) => println!("Cannot save {}: {}", path, err),
+		}
+		self.paper.clear();
+	}
+}
+
+impl Drop for Printer
+{
+	// SAVE A SHEET THAT WAS NOT CUT YET
+	fn drop(&mut self)
+	{
+		self.cut_paper();
+	}
+}
+
+impl SerialLink for Printer
+{
+	fn exchange(&mut self, outgoing : u8) -> u8
+	{
+		// WAIT FOR THE MAGIC BYTES
+		let index = self.packet.len();
+		if (index == 0 && outgoing != 0x88) || (index == 1 && outgoing != 0x33)
+		{
+			self.packet.clear();
+			return 0x00;
+		}
+		self.packet.push(outgoing);
+		if index < 6
+		{
+			return 0x00;
+		}
+
+		let length = self.data_length();
+		if index == 6 + length + 2
+		{
+			self.process_packet();
+			return 0x81;	// PRINTER CONNECTED
+		}
+		if index == 6 + length + 3
+		{
+			let status = self.status;
+			if self.packet[2] == CMD_STATUS
+			{
+				self.status &= !STATUS_PRINTING;	// THE PRINT IS DONE ONCE REPORTED
+			}
+			self.packet.clear();
+			return status;
+		}
+		0x00
+	}
+
+	// The printer never drives the clock
+	fn poll(&mut self, _data : u8, _ready : bool) -> Option<u8>
+	{
+		None
+	}
+}
+
+// Run length encoding: a control byte with bit 7 set repeats the next byte (control & 0x7F) + 2 times,
+// otherwise (control + 1) bytes are copied
+fn decompress(data : &[u8]) -> Vec<u8>
+{
+	let mut output = Vec::new();
+	let mut i = 0;
+	while i < data.len()
+	{
+		let control = data[i];
+		i += 1;
+		if control & 0x80 != 0
+		{
+			if let Some(&byte) = data.get(i)
+			{
+				output.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+			}
+			i += 1;
+		}
+		else
+		{
+			let end = (i + control as usize + 1).min(data.len());
+			output.extend_from_slice(&data[i..end]);
+			i = end;
+		}
+	}
+	output
+}
+
+fn save_png(path : &str, pixels : &[u8]) -> Result<(), String>
+{
+	let file = File::create(path).map_err(|e| e.to_string())?;
+	let mut encoder = png::Encoder::new(BufWriter::new(file), PAPER_WIDTH as u32, (pixels.len() / PAPER_WIDTH) as u32);
+	encoder.set_color(png::ColorType::Grayscale);
+	encoder.set_depth(png::BitDepth::Eight);
+	let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+	writer.write_image_data(pixels).map_err(|e| e.to_string())
+}
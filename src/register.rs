@@ -1,3 +1,4 @@
+use crate::savestate::StateIo;
 
 pub struct Flag
 {
@@ -121,6 +122,19 @@ impl Register
 		}
 	}
 
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		for register in [&mut self.a, &mut self.b, &mut self.c, &mut self.d, &mut self.e, &mut self.h, &mut self.l]
+		{
+			state.u8(register);
+		}
+		let mut flags = self.f.value;
+		state.u8(&mut flags);
+		self.f.set_value(flags);
+		state.u16(&mut self.program_counter);
+		state.u16(&mut self.stack_pointer);
+	}
+
 
 	pub fn get_af(&self) -> u16
 	{
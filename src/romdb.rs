@@ -0,0 +1,134 @@
+use std::borrow::Cow;
+use std::fs;
+use std::sync::OnceLock;
+use crate::cartridge::CgbSupport;
+use crate::emulator::Model;
+
+// Known dumps, found by CRC32 and confirmed by SHA-1. The values of an entry replace the
+// header fields of the ROM, wrong in some hacks and bootlegs. The built-in table is extended
+// by a database file (romdb.cfg), one ROM per line with '|' between the fields:
+//     crc32 | sha1 | cartridge type | cgb | sgb | model | region | title
+//     1A2B3C4D | 0123456789abcdef0123456789abcdef01234567 | 0x1B | enhanced | yes | cgb | USA | Some Hack
+// cgb is none, enhanced or only, sgb is yes or no, model is dmg, cgb or sgb. Lines starting
+// with '#' are comments. Entries of the file are looked up before the built-in ones.
+
+pub const DEFAULT_ROMDB_FILE : &str = "romdb.cfg";
+
+pub struct KnownRom
+{
+	pub crc32 : u32,
+	pub sha1 : Cow<'static, str>,		//Lowercase hex digest
+	pub title : Cow<'static, str>,		//Canonical title
+	pub region : Cow<'static, str>,
+	pub cartridge_type : u8,			//Header code of the real mapper
+	pub cgb : CgbSupport,
+	pub sgb : bool,						//Uses the SGB functions
+	pub model : Model,					//Model to run it on
+}
+
+// Built-in entries: clean dumps only, hacks and bad dumps go in the database file. The MBC
+// cartridges cannot be run yet, their entries are used by the info command.
+pub const KNOWN_ROMS : [KnownRom; 9] =
+[
+	KnownRom { crc32 : 0x46DF91AD, sha1 : Cow::Borrowed("74591cc9501af93873f9a5d3eb12da12c0723bbc"), title : Cow::Borrowed("Tetris (Rev A)"), region : Cow::Borrowed("World"),
+		cartridge_type : 0x00, cgb : CgbSupport::None, sgb : false, model : Model::Dmg },
+	KnownRom { crc32 : 0x9F7FDD53, sha1 : Cow::Borrowed("ea9bcae617fdf159b045185467ae58b2e4a48b9a"), title : Cow::Borrowed("Pokemon - Red Version"), region : Cow::Borrowed("USA, Europe"),
+		cartridge_type : 0x13, cgb : CgbSupport::None, sgb : true, model : Model::Sgb },
+	KnownRom { crc32 : 0xD6DA8A1A, sha1 : Cow::Borrowed("d7037c83e1ae5b39bde3c30787637ba1d4c48ce2"), title : Cow::Borrowed("Pokemon - Blue Version"), region : Cow::Borrowed("USA, Europe"),
+		cartridge_type : 0x13, cgb : CgbSupport::None, sgb : true, model : Model::Sgb },
+	KnownRom { crc32 : 0x7D527D62, sha1 : Cow::Borrowed("cc7d03262ebfaf2f06772c1a480c7d9d5f4a38e1"), title : Cow::Borrowed("Pokemon - Yellow Version"), region : Cow::Borrowed("USA, Europe"),
+		cartridge_type : 0x1B, cgb : CgbSupport::Enhanced, sgb : true, model : Model::Cgb },
+	KnownRom { crc32 : 0x6BDE3C3E, sha1 : Cow::Borrowed("d8b8a3600a465308c9953dfa04f0081c05bdcb3d"), title : Cow::Borrowed("Pokemon - Gold Version"), region : Cow::Borrowed("USA, Europe"),
+		cartridge_type : 0x10, cgb : CgbSupport::Enhanced, sgb : true, model : Model::Cgb },
+	KnownRom { crc32 : 0x8AD48636, sha1 : Cow::Borrowed("49b163f7e57702bc939d642a18f591de55d92dae"), title : Cow::Borrowed("Pokemon - Silver Version"), region : Cow::Borrowed("USA, Europe"),
+		cartridge_type : 0x10, cgb : CgbSupport::Enhanced, sgb : true, model : Model::Cgb },
+	KnownRom { crc32 : 0x3358E30A, sha1 : Cow::Borrowed("f2f52230b536214ef7c9924f483392993e226cfb"), title : Cow::Borrowed("Pokemon - Crystal Version (Rev 1)"), region : Cow::Borrowed("USA, Europe"),
+		cartridge_type : 0x10, cgb : CgbSupport::Only, sgb : false, model : Model::Cgb },
+	KnownRom { crc32 : 0xB074356D, sha1 : Cow::Borrowed("a979a7321b63b8e744d75d6aa7866b1e00d43da8"), title : Cow::Borrowed("cpu_instrs (Blargg's test ROMs)"), region : Cow::Borrowed("World"),
+		cartridge_type : 0x01, cgb : CgbSupport::Enhanced, sgb : false, model : Model::Dmg },
+	KnownRom { crc32 : 0x02B9A055, sha1 : Cow::Borrowed("e04b6f1b754fca299bea97fc54d00763a6f6b3ef"), title : Cow::Borrowed("dmg-acid2 (PPU test)"), region : Cow::Borrowed("World"),
+		cartridge_type : 0x00, cgb : CgbSupport::None, sgb : false, model : Model::Dmg },
+];
+
+// Entries of the database file, loaded once at startup
+static LOADED_ROMS : OnceLock<Vec<KnownRom>> = OnceLock::new();
+
+// Load the database file, a missing file adds no entry. Returns the number of entries.
+pub fn load_database(path : &str) -> Result<usize, String>
+{
+	let Ok(text) = fs::read_to_string(path) else { return Ok(0) };
+	let mut roms = Vec::new();
+	for (number, line) in text.lines().enumerate()
+	{
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#')
+		{
+			continue;
+		}
+		roms.push(parse_entry(line).map_err(|err| format!("{}:{}: {}", path, number + 1, err))?);
+	}
+	let count = roms.len();
+	LOADED_ROMS.set(roms).map_err(|_| "the ROM database is already loaded".to_string())?;
+	Ok(count)
+}
+
+fn parse_entry(line : &str) -> Result<KnownRom, String>
+{
+	let fields : Vec<&str> = line.splitn(8, '|').map(str::trim).collect();
+	let [crc32, sha1, cartridge_type, cgb, sgb, model, region, title] = fields[..] else
+	{
+		return Err("expected \"crc32 | sha1 | cartridge type | cgb | sgb | model | region | title\"".to_string());
+	};
+	let crc32 = u32::from_str_radix(crc32, 16).map_err(|_| format!("invalid CRC32 \"{}\"", crc32))?;
+	if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit())
+	{
+		return Err(format!("invalid SHA-1 \"{}\"", sha1));
+	}
+	let cartridge_type = u8::from_str_radix(cartridge_type.trim_start_matches("0x"), 16)
+		.map_err(|_| format!("invalid cartridge type \"{}\"", cartridge_type))?;
+	let cgb = match cgb
+	{
+		"none" => CgbSupport::None,
+		"enhanced" => CgbSupport::Enhanced,
+		"only" => CgbSupport::Only,
+		_ => return Err(format!("unknown CGB support \"{}\", expected none, enhanced or only", cgb)),
+	};
+	let sgb = match sgb
+	{
+		"yes" => true,
+		"no" => false,
+		_ => return Err(format!("expected yes or no for SGB support, not \"{}\"", sgb)),
+	};
+	let model = match model
+	{
+		"dmg" => Model::Dmg,
+		"cgb" => Model::Cgb,
+		"sgb" => Model::Sgb,
+		_ => return Err(format!("unknown model \"{}\", expected dmg, cgb or sgb", model)),
+	};
+
+	Ok(KnownRom
+	{
+		crc32,
+		sha1 : Cow::Owned(sha1.to_ascii_lowercase()),
+		title : Cow::Owned(title.to_string()),
+		region : Cow::Owned(region.to_string()),
+		cartridge_type,
+		cgb,
+		sgb,
+		model,
+	})
+}
+
+// Entry of a ROM, None when it's unknown or when only the CRC32 matches
+pub fn lookup(data : &[u8]) -> Option<&'static KnownRom>
+{
+	let crc32 = crc32fast::hash(data);
+	let mut candidates = LOADED_ROMS.get().into_iter().flatten()
+		.chain(KNOWN_ROMS.iter())
+		.filter(|rom| rom.crc32 == crc32)
+		.peekable();
+	candidates.peek()?;
+	let sha1 = sha1_smol::Sha1::from(data).digest().to_string();
+	candidates.find(|rom| rom.sha1 == sha1)
+}
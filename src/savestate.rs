@@ -0,0 +1,263 @@
+use std::fmt;
+
+// Save state file:
+//     "RBST" | format version (u16) | emulator version (length + text) | model (u8) | ROM SHA-1 (20 bytes) | state
+// Every value is little endian. The same sync function of each component writes the state
+// and reads it back, fields added by a later format version are only read from states of
+// that version (see StateIo::version), older states keep their initial value.
+// Format versions:
+//     1: first version
+//     2: DMG compatibility palettes of the PPU (picked at boot on CGB)
+
+const MAGIC : &[u8; 4] = b"RBST";
+pub const STATE_VERSION : u16 = 2;
+pub const STATE_EXTENSION : &str = "state";
+
+#[derive(Debug)]
+pub enum StateError
+{
+	NotAState,							//Bad magic bytes
+	Truncated,
+	NewerVersion(u16),					//Made by a newer emulator
+	WrongRom,							//Made with another ROM
+	BadValue(String),
+}
+
+impl fmt::Display for StateError
+{
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			StateError::NotAState => write!(f, "not a save state"),
+			StateError::Truncated => write!(f, "the save state is truncated"),
+			StateError::NewerVersion(version) => write!(f, "the save state format version {} is newer than this emulator (version {})", version, STATE_VERSION),
+			StateError::WrongRom => write!(f, "the save state was made with another ROM"),
+			StateError::BadValue(reason) => write!(f, "bad value in the save state: {}", reason),
+		}
+	}
+}
+
+// Header of a save state
+pub struct StateHeader
+{
+	pub version : u16,
+	pub emulator_version : String,
+	pub model : u8,
+	pub rom_hash : [u8; 20],
+}
+
+impl StateHeader
+{
+	pub fn init_header() -> StateHeader
+	{
+		StateHeader
+		{
+			version : STATE_VERSION,
+			emulator_version : env!("CARGO_PKG_VERSION").to_string(),
+			model : 0,
+			rom_hash : [0; 20],
+		}
+	}
+}
+
+// Writes the state of the components when saving, overwrites it when loading
+pub struct StateIo<'a>
+{
+	pub loading : bool,
+	pub version : u16,					//Format version of the state being read
+	data : Vec<u8>,						//Written state
+	input : &'a [u8],					//State being read
+	position : usize,
+	error : Option<StateError>,			//First error of a load, the next reads are ignored
+}
+
+impl<'a> StateIo<'a>
+{
+	pub fn init_saving() -> StateIo<'static>
+	{
+		StateIo
+		{
+			loading : false,
+			version : STATE_VERSION,
+			data : Vec::new(),
+			input : &[],
+			position : 0,
+			error : None,
+		}
+	}
+
+	pub fn init_loading(input : &'a [u8]) -> StateIo<'a>
+	{
+		StateIo
+		{
+			loading : true,
+			version : STATE_VERSION,
+			data : Vec::new(),
+			input,
+			position : 0,
+			error : None,
+		}
+	}
+
+	// Saved bytes
+	pub fn into_data(self) -> Vec<u8>
+	{
+		self.data
+	}
+
+	// Result of a load, the state must have been read entirely
+	pub fn finish(self) -> Result<(), StateError>
+	{
+		match self.error
+		{
+			Some(error) => Err(error),
+			None if self.position != self.input.len() => Err(StateError::BadValue("unexpected data at the end".to_string())),
+			None => Ok(()),
+		}
+	}
+
+	pub fn fail(&mut self, error : StateError)
+	{
+		self.error.get_or_insert(error);
+	}
+
+	pub fn bytes(&mut self, value : &mut [u8])
+	{
+		if !self.loading
+		{
+			self.data.extend_from_slice(value);
+			return;
+		}
+		let end = self.position + value.len();
+		if self.error.is_some() || end > self.input.len()
+		{
+			self.fail(StateError::Truncated);
+			return;
+		}
+		value.copy_from_slice(&self.input[self.position..end]);
+		self.position = end;
+	}
+
+	pub fn u8(&mut self, value : &mut u8)
+	{
+		self.bytes(std::slice::from_mut(value));
+	}
+
+	pub fn bool(&mut self, value : &mut bool)
+	{
+		let mut byte = *value as u8;
+		self.u8(&mut byte);
+		*value = byte != 0;
+	}
+
+	pub fn u16(&mut self, value : &mut u16)
+	{
+		let mut bytes = value.to_le_bytes();
+		self.bytes(&mut bytes);
+		*value = u16::from_le_bytes(bytes);
+	}
+
+	pub fn u32(&mut self, value : &mut u32)
+	{
+		let mut bytes = value.to_le_bytes();
+		self.bytes(&mut bytes);
+		*value = u32::from_le_bytes(bytes);
+	}
+
+	pub fn u64(&mut self, value : &mut u64)
+	{
+		let mut bytes = value.to_le_bytes();
+		self.bytes(&mut bytes);
+		*value = u64::from_le_bytes(bytes);
+	}
+
+	pub fn i32(&mut self, value : &mut i32)
+	{
+		let mut bytes = value.to_le_bytes();
+		self.bytes(&mut bytes);
+		*value = i32::from_le_bytes(bytes);
+	}
+
+	pub fn f32(&mut self, value : &mut f32)
+	{
+		let mut bytes = value.to_le_bytes();
+		self.bytes(&mut bytes);
+		*value = f32::from_le_bytes(bytes);
+	}
+
+	// Register field or counter, checked against its largest value when loading
+	pub fn u8_upto(&mut self, value : &mut u8, max : u8)
+	{
+		let mut byte = *value;
+		self.u8(&mut byte);
+		if byte > max
+		{
+			self.fail(StateError::BadValue(format!("value {} above {}", byte, max)));
+			return;
+		}
+		*value = byte;
+	}
+
+	// Index into a table, checked against its size when loading
+	pub fn index(&mut self, value : &mut usize, size : usize)
+	{
+		let mut index = *value as u32;
+		self.u32(&mut index);
+		if index as usize >= size
+		{
+			self.fail(StateError::BadValue(format!("index {} out of 0..{}", index, size)));
+			return;
+		}
+		*value = index as usize;
+	}
+
+	pub fn u16s(&mut self, values : &mut [u16])
+	{
+		for value in values.iter_mut()
+		{
+			self.u16(value);
+		}
+	}
+
+	// Buffer of a variable size, at most max bytes
+	pub fn vec(&mut self, value : &mut Vec<u8>, max : usize)
+	{
+		let mut length = value.len() as u32;
+		self.u32(&mut length);
+		if length as usize > max
+		{
+			self.fail(StateError::BadValue(format!("buffer of {} bytes, at most {} expected", length, max)));
+			return;
+		}
+		value.resize(length as usize, 0);
+		self.bytes(value);
+	}
+
+	pub fn header(&mut self, header : &mut StateHeader)
+	{
+		let mut magic = *MAGIC;
+		self.bytes(&mut magic);
+		if &magic != MAGIC
+		{
+			self.fail(StateError::NotAState);
+			return;
+		}
+		self.u16(&mut header.version);
+		let mut version = header.emulator_version.as_bytes().to_vec();
+		self.vec(&mut version, 64);
+		header.emulator_version = String::from_utf8_lossy(&version).to_string();
+		self.u8(&mut header.model);
+		self.bytes(&mut header.rom_hash);
+		if header.version > STATE_VERSION
+		{
+			self.fail(StateError::NewerVersion(header.version));
+		}
+		self.version = header.version;
+	}
+
+	pub fn has_error(&self) -> bool
+	{
+		self.error.is_some()
+	}
+}
@@ -0,0 +1,182 @@
+use crate::bus::MemoryBus;
+
+// RAM search: a snapshot of the RAM is taken, then each filter compares the RAM with the
+// previous snapshot and keeps the matching addresses. Searched: external RAM, WRAM and HRAM
+// as mapped at the time of the search.
+
+const REGIONS : [(u16, u16); 3] = [(0xA000, 0xBFFF), (0xC000, 0xDFFF), (0xFF80, 0xFFFE)];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Width
+{
+	Byte,
+	Word,								// Little endian, like the CPU
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter
+{
+	Equal,								// Same value as the previous snapshot
+	Changed,
+	Increased,
+	Decreased,
+	Value(i32),							// Specific value
+}
+
+impl Filter
+{
+	pub fn from_words(words : &[&str]) -> Option<Filter>
+	{
+		match words
+		{
+			["equal"] => Some(Filter::Equal),
+			["changed"] => Some(Filter::Changed),
+			["increased"] => Some(Filter::Increased),
+			["decreased"] => Some(Filter::Decreased),
+			["value", value] => parse_number(value).map(Filter::Value),
+			_ => None,
+		}
+	}
+}
+
+// Decimal or 0x prefixed hexadecimal, can be negative
+pub fn parse_number(text : &str) -> Option<i32>
+{
+	let (negative, digits) = match text.strip_prefix('-') { Some(digits) => (true, digits), None => (false, text) };
+	let value = match digits.strip_prefix("0x").or(digits.strip_prefix("0X"))
+	{
+		Some(hex) => i32::from_str_radix(hex, 16).ok()?,
+		None => digits.parse::<i32>().ok()?,
+	};
+	Some(if negative { -value } else { value })
+}
+
+pub struct RamSearch
+{
+	pub width : Width,
+	pub signed : bool,
+	pub candidates : Vec<u16>,			//Addresses still matching
+	snapshot : Vec<u8>,					//RAM at the last filter, indexed by address
+}
+
+impl RamSearch
+{
+	// New search, every address is a candidate
+	pub fn start(bus : &MemoryBus, width : Width, signed : bool) -> RamSearch
+	{
+		let size = if width == Width::Word { 2 } else { 1 };
+		let candidates = REGIONS.iter()
+			.flat_map(|&(start, end)| start..=end + 1 - size)
+			.collect();
+		RamSearch
+		{
+			width,
+			signed,
+			candidates,
+			snapshot : take_snapshot(bus),
+		}
+	}
+
+	// Value at an address in a snapshot
+	fn value(&self, snapshot : &[u8], address : u16) -> i32
+	{
+		let address = address as usize;
+		match (self.width, self.signed)
+		{
+			(Width::Byte, false) => snapshot[address] as i32,
+			(Width::Byte, true) => snapshot[address] as i8 as i32,
+			(Width::Word, false) => u16::from_le_bytes([snapshot[address], snapshot[address + 1]]) as i32,
+			(Width::Word, true) => i16::from_le_bytes([snapshot[address], snapshot[address + 1]]) as i32,
+		}
+	}
+
+	// Keep the candidates matching the filter, returns how many are left
+	pub fn filter(&mut self, bus : &MemoryBus, filter : Filter) -> usize
+	{
+		let current = take_snapshot(bus);
+		let candidates = std::mem::take(&mut self.candidates);
+		self.candidates = candidates.into_iter().filter(|&address|
+		{
+			let (new, old) = (self.value(&current, address), self.value(&self.snapshot, address));
+			match filter
+			{
+				Filter::Equal => new == old,
+				Filter::Changed => new != old,
+				Filter::Increased => new > old,
+				Filter::Decreased => new < old,
+				Filter::Value(value) => new == value,
+			}
+		}).collect();
+		self.snapshot = current;
+		self.candidates.len()
+	}
+
+	// Candidates with their value at the last filter
+	pub fn results(&self) -> Vec<(u16, i32)>
+	{
+		self.candidates.iter().map(|&address| (address, self.value(&self.snapshot, address))).collect()
+	}
+}
+
+fn take_snapshot(bus : &MemoryBus) -> Vec<u8>
+{
+	let mut snapshot = vec![0; 0x10000];
+	for &(start, end) in REGIONS.iter()
+	{
+		for address in start..=end
+		{
+			snapshot[address as usize] = bus.read_byte(address);
+		}
+	}
+	snapshot
+}
+
+// GameShark codes writing a value at an address (two codes for a word). WRAM bank 1-7 is
+// selected on CGB, HRAM cannot be written by a GameShark.
+pub fn gameshark_codes(bus : &MemoryBus, address : u16, value : i32, width : Width) -> Result<Vec<String>, String>
+{
+	// SIGNED OR UNSIGNED, THE VALUE MUST FIT THE WIDTH OF THE SEARCH
+	let (min, max, name) = match width
+	{
+		Width::Byte => (i8::MIN as i32, u8::MAX as i32, "a byte"),
+		Width::Word => (i16::MIN as i32, u16::MAX as i32, "a word"),
+	};
+	if !(min..=max).contains(&value)
+	{
+		return Err(format!("{} does not fit in {} ({} to {})", value, name, min, max));
+	}
+
+	let bytes = match width
+	{
+		Width::Byte => vec![value as u8],
+		Width::Word => (value as u16).to_le_bytes().to_vec(),
+	};
+	let mut codes = Vec::new();
+	for (i, &byte) in bytes.iter().enumerate()
+	{
+		let address = address.wrapping_add(i as u16);
+		if !(0xA000..=0xDFFF).contains(&address)
+		{
+			return Err(format!("0x{:04X} cannot be written by a GameShark code (RAM 0xA000-0xDFFF only)", address));
+		}
+		let bank = if bus.cgb_mode && address >= 0xD000 { 0x90 | bus.wram_bank as u8 } else { 0x01 };
+		codes.push(format!("{:02X}{:02X}{:02X}{:02X}", bank, byte, address & 0xFF, address >> 8));
+	}
+	Ok(codes)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn gameshark_values_must_fit()
+	{
+		let bus = MemoryBus::init_bus();
+		assert_eq!(gameshark_codes(&bus, 0xC100, -1, Width::Byte).unwrap(), ["01FF00C1"]);
+		assert!(gameshark_codes(&bus, 0xC100, 300, Width::Byte).is_err());
+		assert_eq!(gameshark_codes(&bus, 0xC100, 300, Width::Word).unwrap(), ["012C00C1", "010101C1"]);
+		assert!(gameshark_codes(&bus, 0xC100, 0x10000, Width::Word).is_err());
+	}
+}
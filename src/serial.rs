@@ -0,0 +1,168 @@
+use crate::savestate::StateIo;
+
+// Serial port: SB holds the byte being shifted out (MSB first) while the bits of the
+// partner are shifted in. With the internal clock a bit is shifted every 512 CPU cycles (8192 Hz).
+pub const SB : u16 = 0xFF01;	//Serial transfer data
+pub const SC : u16 = 0xFF02;	//Serial control (bit 7 start, bit 1 CGB fast clock, bit 0 internal clock)
+
+const BIT_CYCLES : u32 = 512;		// 8192 Hz
+const FAST_BIT_CYCLES : u32 = 16;	// 262144 Hz (CGB)
+const POLL_CYCLES : u32 = 64;		// Cycles between two checks of the link for a transfer clocked by the partner
+
+// Other end of the link cable
+pub trait SerialLink
+{
+	// Internal clock: send a byte at the end of a transfer and get the partner's byte (0xFF if it's not listening)
+	fn exchange(&mut self, outgoing : u8) -> u8;
+
+	// External clock: byte transferred by the partner since the last poll, if any.
+	// data is the byte shifted out to the partner, only used when ready (transfer started with the external clock).
+	fn poll(&mut self, data : u8, ready : bool) -> Option<u8>;
+}
+
+pub struct Serial
+{
+	pub cgb : bool,						//CGB mode (fast clock available)
+	pub data : u8,						//SB
+	pub control : u8,					//SC
+	timer : u32,						//Cycles until the next bit
+	bits : u8,							//Bits left in the transfer
+	outgoing : u8,						//Byte sent by the current transfer
+	poll_timer : u32,
+
+	pub link : Option<Box<dyn SerialLink>>,	//Partner, none when the cable is unplugged
+	pub output : Vec<u8>,				//Bytes sent by the game, drained by the host
+}
+
+impl Serial
+{
+	pub fn init_serial() -> Serial
+	{
+		Serial
+		{
+			cgb : false,
+			data : 0,
+			control : 0,
+			timer : 0,
+			bits : 0,
+			outgoing : 0,
+			poll_timer : 0,
+			link : None,
+			output : Vec::new(),
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u8(&mut self.data);
+		state.u8(&mut self.control);
+		state.u32(&mut self.timer);
+		state.u8(&mut self.bits);
+		state.u8(&mut self.outgoing);
+		state.u32(&mut self.poll_timer);
+	}
+
+	pub fn read_byte(&self, address : u16) -> u8
+	{
+		match address
+		{
+			SB => self.data,
+			SC if self.cgb => 0x7C | self.control,
+			SC => 0x7E | self.control,
+			_ => 0xFF,
+		}
+	}
+
+	pub fn write_byte(&mut self, address : u16, value : u8)
+	{
+		match address
+		{
+			SB => self.data = value,
+			SC =>
+			{
+				self.control = value & if self.cgb { 0x83 } else { 0x81 };
+				if self.transferring() && self.internal_clock()
+				{
+					self.bits = 8;
+					self.outgoing = self.data;
+					self.timer = self.bit_cycles();
+				}
+			},
+			_ => (),
+		}
+	}
+
+	fn transferring(&self) -> bool
+	{
+		self.control & 0x80 != 0
+	}
+
+	fn internal_clock(&self) -> bool
+	{
+		self.control & 0x01 != 0
+	}
+
+	fn bit_cycles(&self) -> u32
+	{
+		if self.control & 0x02 != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES }
+	}
+
+	// Advance by a number of CPU cycles, returns true if the serial interrupt must be requested.
+	// With an external clock and no partner the transfer never ends.
+	pub fn step(&mut self, cycles : u32) -> bool
+	{
+		if self.link.is_some()
+		{
+			self.poll_timer += cycles;
+			if self.poll_timer >= POLL_CYCLES && self.poll_link()
+			{
+				return true;
+			}
+		}
+
+		if !self.transferring() || !self.internal_clock() || self.bits == 0
+		{
+			return false;
+		}
+
+		let mut cycles = cycles;
+		while cycles >= self.timer
+		{
+			cycles -= self.timer;
+			self.timer = self.bit_cycles();
+
+			// NO PARTNER: THE INPUT LINE IS PULLED UP, 1 IS SHIFTED IN
+			self.data = (self.data << 1) | 0x01;
+			self.bits -= 1;
+			if self.bits == 0
+			{
+				// THE PARTNER'S BYTE IS RECEIVED ALL AT ONCE AT THE END OF THE TRANSFER
+				if let Some(link) = self.link.as_mut()
+				{
+					self.data = link.exchange(self.outgoing);
+				}
+				self.control &= 0x7F;
+				self.output.push(self.outgoing);
+				return true;
+			}
+		}
+		self.timer -= cycles;
+		false
+	}
+
+	// Transfer clocked by the partner, returns true when one ended
+	fn poll_link(&mut self) -> bool
+	{
+		self.poll_timer = 0;
+		let ready = self.transferring() && !self.internal_clock();
+		let incoming = match self.link.as_mut().and_then(|link| link.poll(self.data, ready))
+		{
+			Some(incoming) if ready => incoming,
+			_ => return false,
+		};
+		self.output.push(self.data);
+		self.data = incoming;
+		self.control &= 0x7F;
+		true
+	}
+}
@@ -0,0 +1,595 @@
+use crate::ppu::*;
+use crate::savestate::{StateIo, StateError};
+
+// Super Game Boy: commands are sent by the game as 16-byte packets, bit by bit,
+// through the P14/P15 lines of JOYP. The SNES side then colorizes the 160x144
+// screen with 4 palettes selected per 8x8 cell and draws a 256x224 border around it.
+
+pub const SGB_WIDTH : usize = 256;
+pub const SGB_HEIGHT : usize = 224;
+
+// Position of the Game Boy screen inside the border
+const SCREEN_X : usize = 48;
+const SCREEN_Y : usize = 40;
+
+// Commands
+const PAL01 : u8 = 0x00;
+const PAL23 : u8 = 0x01;
+const PAL03 : u8 = 0x02;
+const PAL12 : u8 = 0x03;
+const ATTR_BLK : u8 = 0x04;
+const ATTR_LIN : u8 = 0x05;
+const ATTR_DIV : u8 = 0x06;
+const ATTR_CHR : u8 = 0x07;
+const PAL_SET : u8 = 0x0A;
+const PAL_TRN : u8 = 0x0B;
+const MLT_REQ : u8 = 0x11;
+const CHR_TRN : u8 = 0x13;
+const PCT_TRN : u8 = 0x14;
+const ATTR_TRN : u8 = 0x15;
+const ATTR_SET : u8 = 0x16;
+const MASK_EN : u8 = 0x17;
+
+// Default SGB palette (1-A)
+const DEFAULT_PALETTE : [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+pub struct Sgb
+{
+	pub enabled : bool,					//The cartridge supports SGB functions, packets are ignored otherwise
+
+	packet : [u8; 16],					//Packet being received
+	packet_bit : usize,					//Next bit of the packet, 128 is the stop bit
+	receiving : bool,					//A reset pulse started a packet
+	last_joyp : u8,						//Last P14/P15 value written to JOYP
+	command : Vec<u8>,					//Packets of the current command
+	packets_left : u8,					//Packets still expected for the current command
+
+	pub palettes : [[u16; 4]; 4],		//Palettes 0-3 used on the game screen (color 0 is shared)
+	pub system_palettes : Vec<u16>,		//512 palettes of 4 colors (PAL_TRN)
+	pub attributes : [u8; 20 * 18],		//Palette of each 8x8 cell of the game screen
+	pub attribute_files : Vec<u8>,		//45 attribute files of 90 bytes (ATTR_TRN)
+	pub border_tiles : Vec<u8>,			//256 SNES 4bpp tiles (CHR_TRN)
+	pub border_map : [u16; 32 * 28],	//Border tile map (PCT_TRN)
+	pub border_palettes : [[u16; 16]; 4],	//Border palettes 4-7 (PCT_TRN)
+	pub mask : u8,						//MASK_EN: 0 off, 1 freeze, 2 black, 3 color 0
+
+	pub players : u8,					//MLT_REQ: number of controllers (1, 2 or 4)
+	pub player : u8,					//Controller currently read through JOYP
+
+	framebuffer : Vec<u8>,				//256x224 RGBA output
+}
+
+impl Sgb
+{
+	pub fn init_sgb(enabled : bool) -> Sgb
+	{
+		Sgb
+		{
+			enabled,
+			packet : [0; 16],
+			packet_bit : 0,
+			receiving : false,
+			last_joyp : 0x30,
+			command : Vec::new(),
+			packets_left : 0,
+			palettes : [DEFAULT_PALETTE; 4],
+			system_palettes : vec![0; 512 * 4],
+			attributes : [0; 20 * 18],
+			attribute_files : vec![0; 45 * 90],
+			border_tiles : vec![0; 256 * 32],
+			border_map : [0; 32 * 28],
+			border_palettes : [[0; 16]; 4],
+			mask : 0,
+			players : 1,
+			player : 0,
+			framebuffer : vec![0xFF; SGB_WIDTH * SGB_HEIGHT * 4],
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.bool(&mut self.enabled);
+		state.bytes(&mut self.packet);
+		state.index(&mut self.packet_bit, 129);
+		state.bool(&mut self.receiving);
+		state.u8(&mut self.last_joyp);
+		state.vec(&mut self.command, 16 * 7);
+		state.u8_upto(&mut self.packets_left, 7);
+		if !self.command.len().is_multiple_of(16) || self.command.len() / 16 + self.packets_left as usize > 7
+		{
+			state.fail(StateError::BadValue(format!("SGB command of {} bytes with {} packets left", self.command.len(), self.packets_left)));
+		}
+		for palette in self.palettes.iter_mut()
+		{
+			state.u16s(palette);
+		}
+		state.u16s(&mut self.system_palettes);
+		state.bytes(&mut self.attributes);
+		if self.attributes.iter().any(|&palette| palette > 3)
+		{
+			state.fail(StateError::BadValue("SGB palette above 3".to_string()));
+		}
+		state.bytes(&mut self.attribute_files);
+		state.bytes(&mut self.border_tiles);
+		state.u16s(&mut self.border_map);
+		for palette in self.border_palettes.iter_mut()
+		{
+			state.u16s(palette);
+		}
+		state.u8_upto(&mut self.mask, 3);
+		let mut players = self.players;
+		state.u8(&mut players);
+		match players
+		{
+			1 | 2 | 4 => self.players = players,
+			_ => state.fail(StateError::BadValue(format!("{} SGB players", players))),
+		}
+		state.u8_upto(&mut self.player, self.players - 1);
+		state.bytes(&mut self.framebuffer);
+	}
+
+	// Value of the lower nibble of JOYP when no line is selected (controller ID in multiplayer mode)
+	pub fn joypad_id(&self) -> u8
+	{
+		0x0F - self.player
+	}
+
+	// Every write to JOYP goes through the SGB. vram/lcdc are used by the *_TRN commands.
+	pub fn write_joypad(&mut self, value : u8, vram : &[u8; 0x2000], lcdc : u8)
+	{
+		let joyp = value & 0x30;
+
+		// MLT_REQ: THE NEXT CONTROLLER IS SELECTED WHEN P15 GOES HIGH
+		if self.players > 1 && self.last_joyp & 0x20 == 0 && joyp & 0x20 != 0
+		{
+			self.player = (self.player + 1) & (self.players - 1);
+		}
+
+		match joyp
+		{
+			// RESET PULSE: START OF A PACKET
+			0x00 =>
+			{
+				self.receiving = true;
+				self.packet = [0; 16];
+				self.packet_bit = 0;
+			},
+			// ONE BIT PER PULSE: P14 LOW IS A 0, P15 LOW IS A 1 (EACH PULSE FOLLOWS 0x30)
+			0x10 | 0x20 if self.receiving && self.last_joyp == 0x30 =>
+			{
+				let bit = joyp == 0x10;
+				if self.packet_bit == 128
+				{
+					self.receiving = false;
+					if !bit	// STOP BIT MUST BE 0
+					{
+						self.receive_packet(vram, lcdc);
+					}
+				}
+				else
+				{
+					if bit
+					{
+						self.packet[self.packet_bit / 8] |= 1 << (self.packet_bit % 8);
+					}
+					self.packet_bit += 1;
+				}
+			},
+			_ => (),
+		}
+
+		self.last_joyp = joyp;
+	}
+
+	fn receive_packet(&mut self, vram : &[u8; 0x2000], lcdc : u8)
+	{
+		if !self.enabled
+		{
+			return;
+		}
+
+		// THE FIRST PACKET HOLDS THE COMMAND (BITS 3-7) AND THE NUMBER OF PACKETS (BITS 0-2)
+		if self.packets_left == 0
+		{
+			self.command.clear();
+			self.packets_left = (self.packet[0] & 0x07).max(1);
+		}
+		self.command.extend_from_slice(&self.packet);
+		self.packets_left -= 1;
+
+		if self.packets_left == 0
+		{
+			self.execute(vram, lcdc);
+		}
+	}
+
+	fn execute(&mut self, vram : &[u8; 0x2000], lcdc : u8)
+	{
+		let data = std::mem::take(&mut self.command);
+		match data[0] >> 3
+		{
+			PAL01 => self.set_palettes(&data, 0, 1),
+			PAL23 => self.set_palettes(&data, 2, 3),
+			PAL03 => self.set_palettes(&data, 0, 3),
+			PAL12 => self.set_palettes(&data, 1, 2),
+			ATTR_BLK => self.attr_blk(&data),
+			ATTR_LIN => self.attr_lin(&data),
+			ATTR_DIV => self.attr_div(&data),
+			ATTR_CHR => self.attr_chr(&data),
+			PAL_SET => self.pal_set(&data),
+			PAL_TRN =>
+			{
+				let transfer = Sgb::vram_transfer(vram, lcdc);
+				for (i, color) in self.system_palettes.iter_mut().enumerate()
+				{
+					*color = Sgb::color(&transfer, i * 2);
+				}
+			},
+			MLT_REQ =>
+			{
+				self.players = match data[1] & 0x03 { 1 => 2, 3 => 4, _ => 1 };
+				self.player = 0;
+			},
+			CHR_TRN =>
+			{
+				let start = (data[1] & 0x01) as usize * 0x1000;
+				self.border_tiles[start..start + 0x1000].copy_from_slice(&Sgb::vram_transfer(vram, lcdc));
+			},
+			PCT_TRN =>
+			{
+				let transfer = Sgb::vram_transfer(vram, lcdc);
+				for (i, entry) in self.border_map.iter_mut().enumerate()
+				{
+					*entry = Sgb::color(&transfer, i * 2);
+				}
+				for (i, color) in self.border_palettes.iter_mut().flatten().enumerate()
+				{
+					*color = Sgb::color(&transfer, 0x800 + i * 2);
+				}
+			},
+			ATTR_TRN =>
+			{
+				let transfer = Sgb::vram_transfer(vram, lcdc);
+				self.attribute_files.copy_from_slice(&transfer[..45 * 90]);
+			},
+			ATTR_SET =>
+			{
+				self.apply_attribute_file(data[1] & 0x3F);
+				if data[1] & 0x40 != 0
+				{
+					self.mask = 0;
+				}
+			},
+			MASK_EN => self.mask = data[1] & 0x03,
+			_ => (),	// SOUND, SOU_TRN, DATA_SND, JUMP... ARE NOT EMULATED
+		}
+	}
+
+	// Little-endian 16-bit value (RGB555 color or border map entry)
+	fn color(data : &[u8], index : usize) -> u16
+	{
+		data[index] as u16 | (data[index + 1] as u16) << 8
+	}
+
+	// PAL01/PAL23/PAL03/PAL12: shared color 0 followed by 3 colors for each palette
+	fn set_palettes(&mut self, data : &[u8], first : usize, second : usize)
+	{
+		let color_0 = Sgb::color(data, 1);
+		for palette in self.palettes.iter_mut()
+		{
+			palette[0] = color_0;
+		}
+		for i in 0..3
+		{
+			self.palettes[first][i + 1] = Sgb::color(data, 3 + i * 2);
+			self.palettes[second][i + 1] = Sgb::color(data, 9 + i * 2);
+		}
+	}
+
+	// ATTR_BLK: up to 18 rectangles, each with a palette for the inside, the border and the outside
+	fn attr_blk(&mut self, data : &[u8])
+	{
+		let count = (data[1] as usize).min(18);
+		for set in data[2..].chunks_exact(6).take(count)
+		{
+			let mut control = set[0] & 0x07;
+			let inside = set[1] & 0x03;
+			let mut border = (set[1] >> 2) & 0x03;
+			let outside = (set[1] >> 4) & 0x03;
+			let (x1, y1, x2, y2) = (set[2] & 0x1F, set[3] & 0x1F, set[4] & 0x1F, set[5] & 0x1F);
+
+			// WITH ONLY THE INSIDE OR ONLY THE OUTSIDE SELECTED, THE BORDER TAKES THE SAME PALETTE
+			if control == 0x01
+			{
+				control |= 0x02;
+				border = inside;
+			}
+			else if control == 0x04
+			{
+				control |= 0x02;
+				border = outside;
+			}
+
+			for y in 0..18
+			{
+				for x in 0..20
+				{
+					let in_rectangle = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+					let on_border = in_rectangle && (x == x1 || x == x2 || y == y1 || y == y2);
+					let cell = &mut self.attributes[y as usize * 20 + x as usize];
+					if on_border
+					{
+						if control & 0x02 != 0 { *cell = border; }
+					}
+					else if in_rectangle
+					{
+						if control & 0x01 != 0 { *cell = inside; }
+					}
+					else if control & 0x04 != 0
+					{
+						*cell = outside;
+					}
+				}
+			}
+		}
+	}
+
+	// ATTR_LIN: whole rows or columns set to one palette
+	fn attr_lin(&mut self, data : &[u8])
+	{
+		let count = (data[1] as usize).min(data.len() - 2);
+		for &line in data[2..2 + count].iter()
+		{
+			let number = (line & 0x1F) as usize;
+			let palette = (line >> 5) & 0x03;
+			if line & 0x80 != 0
+			{
+				// HORIZONTAL LINE
+				if number < 18
+				{
+					self.attributes[number * 20..number * 20 + 20].fill(palette);
+				}
+			}
+			else if number < 20
+			{
+				// VERTICAL LINE
+				for y in 0..18
+				{
+					self.attributes[y * 20 + number] = palette;
+				}
+			}
+		}
+	}
+
+	// ATTR_DIV: screen split in two by a line, with a third palette for the line itself
+	fn attr_div(&mut self, data : &[u8])
+	{
+		let after = data[1] & 0x03;
+		let before = (data[1] >> 2) & 0x03;
+		let on_line = (data[1] >> 4) & 0x03;
+		let horizontal = data[1] & 0x40 != 0;
+		let line = data[2] as usize & 0x1F;
+
+		for y in 0..18
+		{
+			for x in 0..20
+			{
+				let position = if horizontal { y } else { x };
+				self.attributes[y * 20 + x] = match position.cmp(&line)
+				{
+					std::cmp::Ordering::Less => before,
+					std::cmp::Ordering::Equal => on_line,
+					std::cmp::Ordering::Greater => after,
+				};
+			}
+		}
+	}
+
+	// ATTR_CHR: one palette per cell starting at (x, y), 4 cells per byte (MSB first)
+	fn attr_chr(&mut self, data : &[u8])
+	{
+		let mut x = data[1] as usize;
+		let mut y = data[2] as usize;
+		let count = (Sgb::color(data, 3) as usize).min(360);
+		let vertical = data[5] & 0x01 != 0;
+
+		for i in 0..count
+		{
+			if x >= 20 || y >= 18 || 6 + i / 4 >= data.len()
+			{
+				break;
+			}
+			self.attributes[y * 20 + x] = (data[6 + i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+
+			if vertical
+			{
+				y += 1;
+				if y == 18 { y = 0; x += 1; }
+			}
+			else
+			{
+				x += 1;
+				if x == 20 { x = 0; y += 1; }
+			}
+		}
+	}
+
+	// PAL_SET: copy 4 palettes from the system palettes, optionally apply an attribute file
+	fn pal_set(&mut self, data : &[u8])
+	{
+		for i in 0..4
+		{
+			let index = (Sgb::color(data, 1 + i * 2) & 0x1FF) as usize * 4;
+			self.palettes[i].copy_from_slice(&self.system_palettes[index..index + 4]);
+		}
+		let color_0 = self.palettes[0][0];
+		for palette in self.palettes.iter_mut()
+		{
+			palette[0] = color_0;
+		}
+
+		if data[9] & 0x80 != 0
+		{
+			self.apply_attribute_file(data[9] & 0x3F);
+		}
+		if data[9] & 0x40 != 0
+		{
+			self.mask = 0;
+		}
+	}
+
+	fn apply_attribute_file(&mut self, file : u8)
+	{
+		if file as usize >= 45
+		{
+			return;
+		}
+		let start = file as usize * 90;
+		for (i, cell) in self.attributes.iter_mut().enumerate()
+		{
+			*cell = (self.attribute_files[start + i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+		}
+	}
+
+	// *_TRN commands copy 4KB from the Game Boy screen: the first 256 tiles shown by the BG map
+	fn vram_transfer(vram : &[u8; 0x2000], lcdc : u8) -> Vec<u8>
+	{
+		let map = if lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+		let mut transfer = Vec::with_capacity(0x1000);
+		for i in 0..256
+		{
+			let tile = vram[map + (i / 20) * 32 + i % 20];
+			let address = if lcdc & 0x10 != 0
+			{
+				tile as usize * 16
+			}
+			else
+			{
+				(0x1000 + tile as i8 as i32 * 16) as usize
+			};
+			transfer.extend_from_slice(&vram[address..address + 16]);
+		}
+		transfer
+	}
+
+	// Compose the border and the colorized game screen from the DMG shades of the frame
+	pub fn render(&mut self, shades : &[u8]) -> &[u8]
+	{
+		let backdrop = self.palettes[0][0];
+
+		// BORDER (COLOR 0 IS TRANSPARENT AND SHOWS THE BACKDROP)
+		for (i, &entry) in self.border_map.iter().enumerate()
+		{
+			let tile = (entry & 0xFF) as usize * 32;
+			let palette = ((entry >> 10) & 0x03) as usize;
+			let (tile_x, tile_y) = ((i % 32) * 8, (i / 32) * 8);
+
+			for row in 0..8
+			{
+				let y = tile_y + row;
+				let tile_row = if entry & 0x8000 != 0 { 7 - row } else { row };
+				for column in 0..8
+				{
+					let x = tile_x + column;
+					if (SCREEN_X..SCREEN_X + 160).contains(&x) && (SCREEN_Y..SCREEN_Y + 144).contains(&y)
+					{
+						continue;
+					}
+					let bit = if entry & 0x4000 != 0 { column } else { 7 - column };
+					let planes = [
+						self.border_tiles[tile + tile_row * 2],
+						self.border_tiles[tile + tile_row * 2 + 1],
+						self.border_tiles[tile + 16 + tile_row * 2],
+						self.border_tiles[tile + 16 + tile_row * 2 + 1],
+					];
+					let color = planes.iter().enumerate()
+						.fold(0, |color, (plane, &byte)| color | ((byte >> bit) & 0x01) << plane) as usize;
+					let rgb = if color == 0 { backdrop } else { self.border_palettes[palette][color] };
+					Sgb::put_pixel(&mut self.framebuffer, x, y, rgb);
+				}
+			}
+		}
+
+		// GAME SCREEN (KEPT AS IS WHILE FROZEN)
+		if self.mask != 1
+		{
+			for y in 0..144
+			{
+				for x in 0..160
+				{
+					let rgb = match self.mask
+					{
+						2 => 0x0000,
+						3 => backdrop,
+						_ =>
+						{
+							let palette = self.attributes[(y / 8) * 20 + x / 8] as usize;
+							self.palettes[palette][shades[y * 160 + x] as usize]
+						},
+					};
+					Sgb::put_pixel(&mut self.framebuffer, SCREEN_X + x, SCREEN_Y + y, rgb);
+				}
+			}
+		}
+
+		&self.framebuffer
+	}
+
+	fn put_pixel(framebuffer : &mut [u8], x : usize, y : usize, rgb555 : u16)
+	{
+		let index = (y * SGB_WIDTH + x) * 4;
+		framebuffer[index..index + 3].copy_from_slice(&PPU::rgb555_to_rgb(rgb555));
+		framebuffer[index + 3] = 0xFF;
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn send_packet(sgb : &mut Sgb, packet : [u8; 16])
+	{
+		sgb.packet = packet;
+		sgb.receive_packet(&[0; 0x2000], 0);
+	}
+
+	#[test]
+	fn attr_blk_count_past_the_packet()
+	{
+		// ONE PACKET HOLDS 2 DATA SETS, THE 3RD ONE ANNOUNCED IS CUT
+		let mut sgb = Sgb::init_sgb(true);
+		let mut packet = [0; 16];
+		packet[0] = ATTR_BLK << 3 | 1;
+		packet[1] = 3;
+		packet[2..8].copy_from_slice(&[0x01, 0x02, 0, 0, 1, 1]);
+		packet[8..14].copy_from_slice(&[0x01, 0x03, 2, 2, 2, 2]);
+		send_packet(&mut sgb, packet);
+
+		assert_eq!(sgb.attributes[0], 2);
+		assert_eq!(sgb.attributes[20 + 1], 2);
+		assert_eq!(sgb.attributes[2 * 20 + 2], 3);
+		assert_eq!(sgb.attributes[3 * 20 + 3], 0);
+	}
+
+	#[test]
+	fn mlt_req_cycles_the_players()
+	{
+		let mut sgb = Sgb::init_sgb(true);
+		let mut packet = [0; 16];
+		packet[0] = MLT_REQ << 3 | 1;
+		packet[1] = 0x03;
+		send_packet(&mut sgb, packet);
+		assert_eq!(sgb.players, 4);
+
+		// P15 GOES LOW THEN HIGH TO SELECT THE NEXT CONTROLLER
+		let mut ids = Vec::new();
+		for _ in 0..4
+		{
+			sgb.write_joypad(0x10, &[0; 0x2000], 0);
+			sgb.write_joypad(0x30, &[0; 0x2000], 0);
+			ids.push(sgb.joypad_id());
+		}
+		assert_eq!(ids, [0x0E, 0x0D, 0x0C, 0x0F]);
+	}
+}
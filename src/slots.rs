@@ -0,0 +1,142 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::emulator::Emulator;
+use crate::savestate::STATE_EXTENSION;
+
+// Save state slots of a ROM: <rom>.state1 to <rom>.state10. A slot file holds a thumbnail of
+// the screen and the time of the save before the save state:
+//     "RBSL" | time (u64, seconds since 1970) | width (u16) | height (u16) | RGBA thumbnail | save state
+
+pub const SLOT_COUNT : usize = 10;
+const SLOT_MAGIC : &[u8; 4] = b"RBSL";
+const THUMBNAIL_SCALE : usize = 2;		// Thumbnails are half the screen size
+
+// Thumbnail and time of a saved slot
+pub struct SlotInfo
+{
+	pub timestamp : u64,				//Seconds since 1970 (UTC)
+	pub width : usize,
+	pub height : usize,
+	pub thumbnail : Vec<u8>,			//RGBA
+}
+
+pub struct SaveSlots
+{
+	rom : String,						//ROM file, the slots are next to it
+	pub current : usize,				//Slot of the save/load state hotkeys, the last one used
+	undo : Option<Vec<u8>>,				//State before the last load
+}
+
+impl SaveSlots
+{
+	pub fn init_slots(rom : &str) -> SaveSlots
+	{
+		SaveSlots
+		{
+			rom : rom.to_string(),
+			current : 0,
+			undo : None,
+		}
+	}
+
+	pub fn path(&self, slot : usize) -> PathBuf
+	{
+		Path::new(&self.rom).with_extension(format!("{}{}", STATE_EXTENSION, slot + 1))
+	}
+
+	pub fn save(&mut self, slot : usize, emulator : &mut Emulator) -> Result<(), String>
+	{
+		let (width, height) = emulator.screen_size();
+		let thumbnail = shrink(emulator.get_framebuffer(), width, height);
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+
+		let mut data = SLOT_MAGIC.to_vec();
+		data.extend_from_slice(&timestamp.to_le_bytes());
+		data.extend_from_slice(&((width / THUMBNAIL_SCALE) as u16).to_le_bytes());
+		data.extend_from_slice(&((height / THUMBNAIL_SCALE) as u16).to_le_bytes());
+		data.extend_from_slice(&thumbnail);
+		data.extend_from_slice(&emulator.save_state());
+		fs::write(self.path(slot), data).map_err(|err| err.to_string())?;
+		self.current = slot;
+		Ok(())
+	}
+
+	// Load a slot, the previous state is kept for undo_load
+	pub fn load(&mut self, slot : usize, emulator : &mut Emulator) -> Result<(), String>
+	{
+		let data = fs::read(self.path(slot)).map_err(|_| format!("slot {} is empty", slot + 1))?;
+		let (_, state) = parse_slot(&data)?;
+		let backup = emulator.save_state();
+		emulator.load_state(state).map_err(|err| err.to_string())?;
+		self.undo = Some(backup);
+		self.current = slot;
+		Ok(())
+	}
+
+	// Go back to the state before the last load
+	pub fn undo_load(&mut self, emulator : &mut Emulator) -> Result<(), String>
+	{
+		let backup = self.undo.take().ok_or("no load to undo")?;
+		emulator.load_state(&backup).map_err(|err| err.to_string())
+	}
+
+	// Thumbnail and time of a slot, None when it's empty or unreadable
+	pub fn info(&self, slot : usize) -> Option<SlotInfo>
+	{
+		let data = fs::read(self.path(slot)).ok()?;
+		parse_slot(&data).ok().map(|(info, _)| info)
+	}
+}
+
+// Thumbnail of an RGBA screen, one pixel per square of THUMBNAIL_SCALE pixels
+fn shrink(screen : &[u8], width : usize, height : usize) -> Vec<u8>
+{
+	let mut thumbnail = Vec::with_capacity(width * height * 4 / (THUMBNAIL_SCALE * THUMBNAIL_SCALE));
+	for y in (0..height / THUMBNAIL_SCALE).map(|y| y * THUMBNAIL_SCALE)
+	{
+		for x in (0..width / THUMBNAIL_SCALE).map(|x| x * THUMBNAIL_SCALE)
+		{
+			let i = (y * width + x) * 4;
+			thumbnail.extend_from_slice(&screen[i..i + 4]);
+		}
+	}
+	thumbnail
+}
+
+// Slot info and save state of a slot file
+fn parse_slot(data : &[u8]) -> Result<(SlotInfo, &[u8]), String>
+{
+	if data.len() < 16 || &data[0..4] != SLOT_MAGIC
+	{
+		return Err("not a save state slot".to_string());
+	}
+	let timestamp = u64::from_le_bytes(data[4..12].try_into().unwrap());
+	let width = u16::from_le_bytes([data[12], data[13]]) as usize;
+	let height = u16::from_le_bytes([data[14], data[15]]) as usize;
+	let end = 16 + width * height * 4;
+	if data.len() < end
+	{
+		return Err("the save state slot is truncated".to_string());
+	}
+	let info = SlotInfo { timestamp, width, height, thumbnail : data[16..end].to_vec() };
+	Ok((info, &data[end..]))
+}
+
+// "YYYY-MM-DD HH:MM" (UTC) of a time in seconds since 1970
+pub fn format_timestamp(timestamp : u64) -> String
+{
+	let (days, seconds) = ((timestamp / 86400) as i64, timestamp % 86400);
+
+	// CIVIL DATE OF A DAY NUMBER, IN ERAS OF 400 YEARS STARTING ON MARCH 1ST
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let day_of_era = z.rem_euclid(146097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
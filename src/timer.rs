@@ -0,0 +1,103 @@
+use crate::savestate::StateIo;
+
+// Timer registers
+pub const DIV : u16 = 0xFF04;
+pub const TIMA : u16 = 0xFF05;
+pub const TMA : u16 = 0xFF06;
+pub const TAC : u16 = 0xFF07;
+
+// Bit of the internal counter driving TIMA for each TAC clock select (4096, 262144, 65536, 16384 Hz)
+const TAC_BITS : [u8; 4] = [9, 3, 5, 7];
+
+pub struct Timer
+{
+	pub counter : u16,	//Internal 16-bit counter, DIV is its upper byte
+	pub tima : u8,		//Timer counter
+	pub tma : u8,		//Timer modulo, reloaded into TIMA on overflow
+	pub tac : u8,		//Timer control: bit 2 enable, bits 0-1 clock select
+}
+
+impl Timer
+{
+	pub fn init_timer() -> Timer
+	{
+		Timer
+		{
+			counter : 0,
+			tima : 0,
+			tma : 0,
+			tac : 0,
+		}
+	}
+
+	pub fn sync_state(&mut self, state : &mut StateIo)
+	{
+		state.u16(&mut self.counter);
+		state.u8(&mut self.tima);
+		state.u8(&mut self.tma);
+		state.u8(&mut self.tac);
+	}
+
+	pub fn read_byte(&self, address : u16) -> u8
+	{
+		match address
+		{
+			DIV => (self.counter >> 8) as u8,
+			TIMA => self.tima,
+			TMA => self.tma,
+			TAC => 0xF8 | self.tac,
+			_ => 0xFF,
+		}
+	}
+
+	// Returns true if the timer interrupt must be requested
+	pub fn write_byte(&mut self, address : u16, value : u8) -> bool
+	{
+		let old_input = self.input();
+		match address
+		{
+			DIV => self.counter = 0,
+			TIMA => self.tima = value,
+			TMA => self.tma = value,
+			TAC => self.tac = value & 0x07,
+			_ => (),
+		}
+
+		// RESETTING DIV OR CHANGING TAC CAN PRODUCE A FALLING EDGE ON THE TIMA INPUT
+		if old_input && !self.input()
+		{
+			return self.increment_tima();
+		}
+		false
+	}
+
+	// Advance the timer by a number of CPU cycles, returns true if TIMA overflowed
+	pub fn step(&mut self, cycles : u32) -> bool
+	{
+		let mut overflow = false;
+		for _ in 0..cycles / 4
+		{
+			let old_input = self.input();
+			self.counter = self.counter.wrapping_add(4);
+			if old_input && !self.input()
+			{
+				overflow |= self.increment_tima();
+			}
+		}
+		overflow
+	}
+
+	// TIMA is clocked on the falling edge of (selected counter bit AND timer enable)
+	fn input(&self) -> bool
+	{
+		let bit = TAC_BITS[(self.tac & 0x03) as usize];
+		self.tac & 0x04 != 0 && (self.counter >> bit) & 0x01 != 0
+	}
+
+	fn increment_tima(&mut self) -> bool
+	{
+		let (tima, overflow) = self.tima.overflowing_add(1);
+		self.tima = if overflow { self.tma } else { tima };
+		overflow
+	}
+}
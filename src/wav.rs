@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use crate::apu::Apu;
+use crate::audio::Resampler;
+
+// 16-bit stereo WAV recording of the APU output, mixed or one file per channel.
+
+pub const WAV_RATE : u32 = 48000;
+const HEADER_SIZE : u32 = 44;
+
+pub struct WavWriter
+{
+	file : BufWriter<File>,
+	frames : u32,						//Stereo frames written
+}
+
+impl WavWriter
+{
+	pub fn create(path : &str, sample_rate : u32) -> io::Result<WavWriter>
+	{
+		let mut wav = WavWriter
+		{
+			file : BufWriter::new(File::create(path)?),
+			frames : 0,
+		};
+		wav.write_header(sample_rate)?;
+		Ok(wav)
+	}
+
+	// RIFF header, the sizes are patched by finish()
+	fn write_header(&mut self, sample_rate : u32) -> io::Result<()>
+	{
+		let data_size = self.frames * 4;
+		let file = &mut self.file;
+		file.write_all(b"RIFF")?;
+		file.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+		file.write_all(b"WAVEfmt ")?;
+		file.write_all(&16u32.to_le_bytes())?;			// FMT CHUNK SIZE
+		file.write_all(&1u16.to_le_bytes())?;			// PCM
+		file.write_all(&2u16.to_le_bytes())?;			// CHANNELS
+		file.write_all(&sample_rate.to_le_bytes())?;
+		file.write_all(&(sample_rate * 4).to_le_bytes())?;	// BYTE RATE
+		file.write_all(&4u16.to_le_bytes())?;			// BLOCK ALIGN
+		file.write_all(&16u16.to_le_bytes())?;			// BITS PER SAMPLE
+		file.write_all(b"data")?;
+		file.write_all(&data_size.to_le_bytes())
+	}
+
+	// Interleaved stereo samples in -1.0..1.0
+	pub fn write_samples(&mut self, samples : &[f32]) -> io::Result<()>
+	{
+		for &sample in samples
+		{
+			let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+			self.file.write_all(&value.to_le_bytes())?;
+		}
+		self.frames += (samples.len() / 2) as u32;
+		Ok(())
+	}
+
+	pub fn finish(mut self) -> io::Result<()>
+	{
+		self.file.seek(SeekFrom::Start(4))?;
+		self.file.write_all(&(HEADER_SIZE - 8 + self.frames * 4).to_le_bytes())?;
+		self.file.seek(SeekFrom::Start(40))?;
+		self.file.write_all(&(self.frames * 4).to_le_bytes())?;
+		self.file.flush()
+	}
+}
+
+pub struct WavRecorder
+{
+	outputs : Vec<(Resampler, WavWriter)>,	//Mixed output, or channels 1-4
+	resampled : Vec<f32>,
+}
+
+impl WavRecorder
+{
+	// Record the mix to path, or each channel to <path>_ch1.wav ... <path>_ch4.wav
+	pub fn start(path : &str, split_channels : bool, apu : &mut Apu) -> io::Result<WavRecorder>
+	{
+		let paths = if split_channels
+		{
+			let stem = path.strip_suffix(".wav").unwrap_or(path);
+			(1..=4).map(|i| format!("{}_ch{}.wav", stem, i)).collect()
+		}
+		else
+		{
+			vec![path.to_string()]
+		};
+
+		let mut outputs = Vec::new();
+		for path in paths.iter()
+		{
+			outputs.push((Resampler::init_resampler(WAV_RATE), WavWriter::create(path, WAV_RATE)?));
+		}
+		apu.split_channels = split_channels;
+
+		Ok(WavRecorder
+		{
+			outputs,
+			resampled : Vec::new(),
+		})
+	}
+
+	// Write the samples produced since the last call, mixed is the APU output already taken by the frontend
+	pub fn record(&mut self, mixed : &[f32], apu : &mut Apu) -> io::Result<()>
+	{
+		let split = self.outputs.len() > 1;
+		for (i, (resampler, wav)) in self.outputs.iter_mut().enumerate()
+		{
+			self.resampled.clear();
+			if split
+			{
+				resampler.process(&std::mem::take(&mut apu.channel_samples[i]), &mut self.resampled);
+			}
+			else
+			{
+				resampler.process(mixed, &mut self.resampled);
+			}
+			wav.write_samples(&self.resampled)?;
+		}
+		Ok(())
+	}
+
+	pub fn finish(self) -> io::Result<()>
+	{
+		for (_, wav) in self.outputs
+		{
+			wav.finish()?;
+		}
+		Ok(())
+	}
+}